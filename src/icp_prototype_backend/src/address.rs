@@ -0,0 +1,210 @@
+use candid::Principal;
+use ic_ledger_types::{AccountIdentifier, Subaccount};
+use std::fmt;
+
+use crate::types::Icrc1Account;
+use crate::Error;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressError {
+    InvalidHex,
+    InvalidLength { expected: usize, actual: usize },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    InvalidPrincipal(String),
+    InvalidSubaccount,
+    NonCanonicalSubaccount,
+    MissingIcrc1Checksum,
+    InvalidIcrc1Checksum { expected: String, actual: String },
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::InvalidHex => write!(f, "Address is not valid hex"),
+            AddressError::InvalidLength { expected, actual } => {
+                write!(f, "Expected {} bytes, got {}", expected, actual)
+            }
+            AddressError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Account identifier checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            AddressError::InvalidPrincipal(e) => write!(f, "Invalid principal: {}", e),
+            AddressError::InvalidSubaccount => write!(f, "Subaccount must be 32 bytes"),
+            AddressError::NonCanonicalSubaccount => {
+                write!(f, "Subaccount is not in canonical form")
+            }
+            AddressError::MissingIcrc1Checksum => write!(f, "ICRC-1 account checksum is missing"),
+            AddressError::InvalidIcrc1Checksum { expected, actual } => write!(
+                f,
+                "ICRC-1 account checksum mismatch: expected {}, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl From<AddressError> for Error {
+    fn from(e: AddressError) -> Self {
+        Error {
+            message: e.to_string(),
+        }
+    }
+}
+
+// An address accepted at the API boundary, resolved to the account identifier the ledger
+// reports in blocks. The ICRC-1 form is kept when the caller supplied it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedAddress {
+    pub account_id: [u8; 32],
+    pub icrc1_account: Option<Icrc1Account>,
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+fn icrc1_checksum(owner: &Principal, subaccount: &[u8; 32]) -> String {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    base32_encode(&hasher.finalize().to_be_bytes())
+}
+
+fn to_subaccount_bytes(subaccount: &Option<Vec<u8>>) -> Result<[u8; 32], AddressError> {
+    match subaccount {
+        Some(bytes) => bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| AddressError::InvalidSubaccount),
+        None => Ok([0; 32]),
+    }
+}
+
+// Decodes a hex account identifier and verifies its CRC32 prefix.
+pub fn parse_account_identifier(hex: &str) -> Result<[u8; 32], AddressError> {
+    let bytes = hex::decode(hex).map_err(|_| AddressError::InvalidHex)?;
+    let account_id: [u8; 32] =
+        bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| AddressError::InvalidLength {
+                expected: 32,
+                actual: bytes.len(),
+            })?;
+
+    let expected = crc32fast::hash(&account_id[4..]);
+    let actual = u32::from_be_bytes([account_id[0], account_id[1], account_id[2], account_id[3]]);
+    if expected != actual {
+        return Err(AddressError::ChecksumMismatch { expected, actual });
+    }
+
+    Ok(account_id)
+}
+
+// Parses the ICRC-1 textual encoding: `<owner>` for the default subaccount, or
+// `<owner>-<checksum>.<subaccount hex without leading zeros>`.
+pub fn parse_icrc1_account(text: &str) -> Result<Icrc1Account, AddressError> {
+    let (owner_and_checksum, subaccount_hex) = match text.rsplit_once('.') {
+        Some(parts) => parts,
+        None => {
+            let owner = Principal::from_text(text)
+                .map_err(|e| AddressError::InvalidPrincipal(e.to_string()))?;
+            return Ok(Icrc1Account {
+                owner,
+                subaccount: None,
+            });
+        }
+    };
+
+    let (owner_text, checksum) = owner_and_checksum
+        .rsplit_once('-')
+        .ok_or(AddressError::MissingIcrc1Checksum)?;
+    let owner = Principal::from_text(owner_text)
+        .map_err(|e| AddressError::InvalidPrincipal(e.to_string()))?;
+
+    if subaccount_hex.is_empty() || subaccount_hex.starts_with('0') || subaccount_hex.len() > 64 {
+        return Err(AddressError::NonCanonicalSubaccount);
+    }
+    let padded = format!("{:0>64}", subaccount_hex);
+    let subaccount: [u8; 32] = hex::decode(padded)
+        .map_err(|_| AddressError::InvalidHex)?
+        .try_into()
+        .map_err(|_| AddressError::InvalidSubaccount)?;
+
+    let expected = icrc1_checksum(&owner, &subaccount);
+    if expected != checksum {
+        return Err(AddressError::InvalidIcrc1Checksum {
+            expected,
+            actual: checksum.to_string(),
+        });
+    }
+
+    Ok(Icrc1Account {
+        owner,
+        subaccount: Some(subaccount.to_vec()),
+    })
+}
+
+// Formats an account in the ICRC-1 textual encoding accepted by `parse_icrc1_account`.
+pub fn format_icrc1_account(account: &Icrc1Account) -> Result<String, AddressError> {
+    let subaccount = to_subaccount_bytes(&account.subaccount)?;
+    if subaccount == [0; 32] {
+        return Ok(account.owner.to_text());
+    }
+
+    let subaccount_hex = hex::encode(subaccount);
+    Ok(format!(
+        "{}-{}.{}",
+        account.owner.to_text(),
+        icrc1_checksum(&account.owner, &subaccount),
+        subaccount_hex.trim_start_matches('0')
+    ))
+}
+
+// Resolves an ICRC-1 account to the account identifier used by the ledger's blocks.
+pub fn icrc1_to_account_identifier(account: &Icrc1Account) -> Result<[u8; 32], AddressError> {
+    let subaccount = to_subaccount_bytes(&account.subaccount)?;
+    let account_id = AccountIdentifier::new(&account.owner, &Subaccount(subaccount));
+    let bytes = hex::decode(account_id.to_hex()).map_err(|_| AddressError::InvalidHex)?;
+    bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| AddressError::InvalidLength {
+            expected: 32,
+            actual: bytes.len(),
+        })
+}
+
+// Accepts either a hex account identifier or an ICRC-1 textual account.
+pub fn parse_address(text: &str) -> Result<ParsedAddress, AddressError> {
+    let text = text.trim();
+    if text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(ParsedAddress {
+            account_id: parse_account_identifier(text)?,
+            icrc1_account: None,
+        });
+    }
+
+    let icrc1_account = parse_icrc1_account(text)?;
+    Ok(ParsedAddress {
+        account_id: icrc1_to_account_identifier(&icrc1_account)?,
+        icrc1_account: Some(icrc1_account),
+    })
+}
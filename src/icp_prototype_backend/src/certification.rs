@@ -0,0 +1,93 @@
+use ic_certification::{fork, labeled, leaf, pruned, AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::types::{StoredTransactions, SweepStatus, TransactionFlag};
+
+// The certified data is the root hash of
+//
+//   fork(
+//     label("next_block", leaf(next block index, u64 big-endian)),
+//     label("transactions", { block index, u64 big-endian -> transaction leaf }),
+//   )
+//
+// Clients check the certificate against the root key, then that the witness digests to the
+// certified data, then look up the leaves of the transactions they were given.
+//
+// A transaction leaf is sha256 of 34 bytes:
+//   block_hash    the ledger's hash of the block (sha256 of its protobuf encoding), or
+//                 32 zero bytes while it is not known yet
+//   sweep_status  0 NotSwept, 1 Swept, 2 FailedToSweep, 3 Refunded
+//   flag          0 none, 1 SubaccountDisabled, 2 SubaccountArchived, 3 WatchOnly
+// The block hash commits to the ledger's own fields, so a client checks those against the
+// ledger's encoding rather than ours.
+
+pub type TransactionTree = RbTree<[u8; 8], Hash>;
+
+pub fn transaction_hash(transaction: &StoredTransactions) -> Hash {
+    let mut leaf = [0u8; 34];
+    if let Some(block_hash) = &transaction.block_hash {
+        if block_hash.len() == 32 {
+            leaf[..32].copy_from_slice(block_hash);
+        }
+    }
+    leaf[32] = match transaction.sweep_status {
+        SweepStatus::NotSwept => 0,
+        SweepStatus::Swept => 1,
+        SweepStatus::FailedToSweep => 2,
+        SweepStatus::Refunded => 3,
+    };
+    leaf[33] = match transaction.flag {
+        None => 0,
+        Some(TransactionFlag::SubaccountDisabled) => 1,
+        Some(TransactionFlag::SubaccountArchived) => 2,
+        Some(TransactionFlag::WatchOnly) => 3,
+    };
+    Sha256::digest(leaf).into()
+}
+
+fn tree(next_block: u64, transactions: HashTree) -> HashTree {
+    fork(
+        labeled("next_block", leaf(next_block.to_be_bytes().to_vec())),
+        labeled("transactions", transactions),
+    )
+}
+
+// Reveals only the cursor, for an empty page
+pub fn cursor_witness(next_block: u64, transactions: &TransactionTree) -> HashTree {
+    tree(next_block, pruned(transactions.root_hash()))
+}
+
+pub fn root_hash(next_block: u64, transactions: &TransactionTree) -> Hash {
+    cursor_witness(next_block, transactions).digest()
+}
+
+// Reveals the entries from first to last inclusive, and proves there are no others between them
+pub fn range_witness(
+    next_block: u64,
+    transactions: &TransactionTree,
+    first: u64,
+    last: u64,
+) -> HashTree {
+    tree(
+        next_block,
+        transactions.value_range(&first.to_be_bytes(), &last.to_be_bytes()),
+    )
+}
+
+// Reveals the entry for one block, or proves that there is none
+pub fn witness(next_block: u64, transactions: &TransactionTree, index: u64) -> HashTree {
+    tree(next_block, transactions.witness(&index.to_be_bytes()))
+}
+
+// Self-describing CBOR, as in the certificate itself
+pub fn encode_witness(witness: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    let encoded = serializer
+        .self_describe()
+        .and_then(|_| witness.serialize(&mut serializer));
+    match encoded {
+        Ok(()) => serializer.into_inner(),
+        Err(_) => Vec::new(),
+    }
+}
@@ -0,0 +1,218 @@
+use sha2::{Digest, Sha256};
+
+use crate::types::{Block, ChainTip, Operation, Transaction};
+
+// The ledger hashes the protobuf encoding of each block (ic_ledger.pb.v1.Block), so we
+// rebuild that encoding from the Candid form. Fields are written in the order prost writes
+// them, and proto3 scalars equal to their default are left out.
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn uint64_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        varint(buf, field << 3);
+        varint(buf, value);
+    }
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    if !value.is_empty() {
+        message_field(buf, field, value);
+    }
+}
+
+// Embedded messages are written whenever they are set, even when empty
+fn message_field(buf: &mut Vec<u8>, field: u64, message: &[u8]) {
+    varint(buf, (field << 3) | 2);
+    varint(buf, message.len() as u64);
+    buf.extend_from_slice(message);
+}
+
+fn message(write: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write(&mut buf);
+    buf
+}
+
+fn tokens(e8s: u64) -> Vec<u8> {
+    message(|buf| uint64_field(buf, 1, e8s))
+}
+
+fn account(hash: &[u8]) -> Vec<u8> {
+    message(|buf| bytes_field(buf, 1, hash))
+}
+
+fn timestamp(nanos: u64) -> Vec<u8> {
+    message(|buf| uint64_field(buf, 1, nanos))
+}
+
+// Burn = 1, Mint = 2, Send = 3; approvals and transfers from an allowance are sends with
+// an extension
+fn transfer(operation: &Operation) -> (u64, Vec<u8>) {
+    match operation {
+        Operation::Burn(data) => (
+            1,
+            message(|buf| {
+                message_field(buf, 1, &account(&data.from));
+                message_field(buf, 3, &tokens(data.amount.e8s));
+                if let Some(spender) = &data.spender {
+                    message_field(buf, 4, &account(spender));
+                }
+            }),
+        ),
+        Operation::Mint(data) => (
+            2,
+            message(|buf| {
+                message_field(buf, 2, &account(&data.to));
+                message_field(buf, 3, &tokens(data.amount.e8s));
+            }),
+        ),
+        Operation::Transfer(data) => (
+            3,
+            message(|buf| {
+                message_field(buf, 1, &account(&data.from));
+                message_field(buf, 2, &account(&data.to));
+                message_field(buf, 3, &tokens(data.amount.e8s));
+                message_field(buf, 4, &tokens(data.fee.e8s));
+                if let Some(spender) = &data.spender {
+                    let transfer_from = message(|buf| message_field(buf, 1, &account(spender)));
+                    message_field(buf, 6, &transfer_from);
+                }
+            }),
+        ),
+        Operation::Approve(data) => (
+            3,
+            message(|buf| {
+                message_field(buf, 1, &account(&data.from));
+                message_field(buf, 2, &account(&data.spender));
+                message_field(buf, 3, &tokens(0));
+                message_field(buf, 4, &tokens(data.fee.e8s));
+                let approve = message(|buf| {
+                    message_field(buf, 1, &tokens(data.allowance.e8s));
+                    if let Some(expires_at) = &data.expires_at {
+                        message_field(buf, 2, &timestamp(expires_at.timestamp_nanos));
+                    }
+                    if let Some(expected) = &data.expected_allowance {
+                        message_field(buf, 3, &tokens(expected.e8s));
+                    }
+                });
+                message_field(buf, 5, &approve);
+            }),
+        ),
+    }
+}
+
+fn encode_transaction(transaction: &Transaction, created_at_time: Option<u64>) -> Vec<u8> {
+    message(|buf| {
+        message_field(
+            buf,
+            4,
+            &message(|buf| uint64_field(buf, 1, transaction.memo)),
+        );
+        if let Some(memo) = &transaction.icrc1_memo {
+            message_field(buf, 7, &message(|buf| bytes_field(buf, 1, memo)));
+        }
+        if let Some(nanos) = created_at_time {
+            message_field(buf, 6, &timestamp(nanos));
+        }
+        if let Some(operation) = &transaction.operation {
+            let (field, transfer) = transfer(operation);
+            message_field(buf, field, &transfer);
+        }
+    })
+}
+
+pub fn encode_block(block: &Block, created_at_time: Option<u64>) -> Vec<u8> {
+    message(|buf| {
+        if let Some(parent_hash) = &block.parent_hash {
+            message_field(buf, 1, &message(|buf| bytes_field(buf, 1, parent_hash)));
+        }
+        message_field(buf, 2, &timestamp(block.timestamp.timestamp_nanos));
+        message_field(
+            buf,
+            3,
+            &encode_transaction(&block.transaction, created_at_time),
+        );
+    })
+}
+
+// The ledger reports a missing created_at_time as the block timestamp, so such a block has
+// two possible hashes until its successor names the right one
+pub fn block_hashes(block: &Block) -> Vec<Vec<u8>> {
+    let created_at_time = block.transaction.created_at_time.timestamp_nanos;
+    let mut encodings = vec![encode_block(block, Some(created_at_time))];
+    if created_at_time == block.timestamp.timestamp_nanos {
+        encodings.push(encode_block(block, None));
+    }
+    encodings
+        .iter()
+        .map(|encoded| Sha256::digest(encoded).to_vec())
+        .collect()
+}
+
+// Each block's hash, taking the candidate its successor names when it has two. The last block
+// stays unresolved if it has two.
+pub fn resolve_hashes(blocks: &[Block]) -> Vec<Option<Vec<u8>>> {
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(position, block)| {
+            let mut hashes = block_hashes(block);
+            let named = blocks
+                .get(position + 1)
+                .and_then(|next| next.parent_hash.as_ref());
+            match named {
+                Some(parent_hash) if hashes.contains(parent_hash) => Some(parent_hash.clone()),
+                _ if hashes.len() == 1 => hashes.pop(),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Checks that each block names its predecessor's hash, starting from the stored tip when the
+// blocks continue it, and returns the new tip
+pub fn verify_chain(
+    tip: &ChainTip,
+    first_index: u64,
+    blocks: &[Block],
+) -> Result<ChainTip, String> {
+    let continues_tip = !tip.hashes.is_empty() && tip.index.checked_add(1) == Some(first_index);
+    let mut previous = if continues_tip {
+        Some(tip.hashes.clone())
+    } else {
+        None
+    };
+
+    for (offset, block) in blocks.iter().enumerate() {
+        let index = first_index + offset as u64;
+        match (&previous, &block.parent_hash) {
+            (Some(hashes), Some(parent_hash)) if !hashes.contains(parent_hash) => {
+                return Err(format!(
+                    "Block {} does not extend block {}",
+                    index,
+                    index - 1
+                ));
+            }
+            (Some(_), None) => {
+                return Err(format!("Block {} has no parent hash", index));
+            }
+            _ => {}
+        }
+        previous = Some(block_hashes(block));
+    }
+
+    Ok(match previous {
+        Some(hashes) if !blocks.is_empty() => ChainTip {
+            index: first_index + blocks.len() as u64 - 1,
+            hashes,
+        },
+        _ => tip.clone(),
+    })
+}
@@ -0,0 +1,332 @@
+use std::fmt;
+
+use crate::types::{
+    Approve, Burn, E8s, Mint, Operation, StoredTransactions, SweepStatus, Timestamp,
+    TransactionDirection, TransactionFlag, Transfer,
+};
+
+// Compact binary form of a stored transaction. Integers are LEB128 varints, byte strings and
+// accounts are length-prefixed, and optional fields and enums take a single tag byte. Field
+// order is fixed by the schema version, so nothing is spent on names or type tables.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    InvalidTag { field: &'static str, tag: u8 },
+    VarintOverflow,
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "Record ends unexpectedly"),
+            DecodeError::InvalidTag { field, tag } => {
+                write!(f, "Invalid tag {} for field {}", tag, field)
+            }
+            DecodeError::VarintOverflow => write!(f, "Varint does not fit in 64 bits"),
+            DecodeError::TrailingBytes(count) => write!(f, "{} trailing bytes", count),
+        }
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    // zigzag, so small negative values stay short
+    fn signed(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.varint(value.len() as u64);
+        self.bytes.extend_from_slice(value);
+    }
+
+    fn opt_varint(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.varint(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn opt_bytes(&mut self, value: Option<&[u8]>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.bytes(value);
+            }
+            None => self.u8(0),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        let value = *self.bytes.get(self.pos).ok_or(DecodeError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(value)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(DecodeError::VarintOverflow);
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(DecodeError::VarintOverflow);
+            }
+        }
+    }
+
+    fn signed(&mut self) -> Result<i64, DecodeError> {
+        let value = self.varint()?;
+        Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.varint()? as usize;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let value = self.bytes[self.pos..end].to_vec();
+        self.pos = end;
+        Ok(value)
+    }
+
+    fn option(&mut self, field: &'static str) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag { field, tag }),
+        }
+    }
+
+    fn opt_varint(&mut self, field: &'static str) -> Result<Option<u64>, DecodeError> {
+        if self.option(field)? {
+            Ok(Some(self.varint()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn opt_bytes(&mut self, field: &'static str) -> Result<Option<Vec<u8>>, DecodeError> {
+        if self.option(field)? {
+            Ok(Some(self.bytes()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn write_operation(writer: &mut Writer, operation: &Option<Operation>) {
+    match operation {
+        None => writer.u8(0),
+        Some(Operation::Approve(data)) => {
+            writer.u8(1);
+            writer.varint(data.fee.e8s);
+            writer.bytes(&data.from);
+            writer.signed(data.allowance_e8s);
+            writer.varint(data.allowance.e8s);
+            writer.opt_varint(data.expected_allowance.as_ref().map(|e8s| e8s.e8s));
+            writer.opt_varint(data.expires_at.as_ref().map(|t| t.timestamp_nanos));
+            writer.bytes(&data.spender);
+        }
+        Some(Operation::Burn(data)) => {
+            writer.u8(2);
+            writer.bytes(&data.from);
+            writer.varint(data.amount.e8s);
+            writer.opt_bytes(data.spender.as_deref());
+        }
+        Some(Operation::Mint(data)) => {
+            writer.u8(3);
+            writer.bytes(&data.to);
+            writer.varint(data.amount.e8s);
+        }
+        Some(Operation::Transfer(data)) => {
+            writer.u8(4);
+            writer.bytes(&data.to);
+            writer.varint(data.fee.e8s);
+            writer.bytes(&data.from);
+            writer.varint(data.amount.e8s);
+            writer.opt_bytes(data.spender.as_deref());
+        }
+    }
+}
+
+fn read_operation(reader: &mut Reader) -> Result<Option<Operation>, DecodeError> {
+    let operation = match reader.u8()? {
+        0 => return Ok(None),
+        1 => Operation::Approve(Approve {
+            fee: E8s {
+                e8s: reader.varint()?,
+            },
+            from: reader.bytes()?,
+            allowance_e8s: reader.signed()?,
+            allowance: E8s {
+                e8s: reader.varint()?,
+            },
+            expected_allowance: reader
+                .opt_varint("expected_allowance")?
+                .map(|e8s| E8s { e8s }),
+            expires_at: reader.opt_varint("expires_at")?.map(Timestamp::from_nanos),
+            spender: reader.bytes()?,
+        }),
+        2 => Operation::Burn(Burn {
+            from: reader.bytes()?,
+            amount: E8s {
+                e8s: reader.varint()?,
+            },
+            spender: reader.opt_bytes("spender")?,
+        }),
+        3 => Operation::Mint(Mint {
+            to: reader.bytes()?,
+            amount: E8s {
+                e8s: reader.varint()?,
+            },
+        }),
+        4 => Operation::Transfer(Transfer {
+            to: reader.bytes()?,
+            fee: E8s {
+                e8s: reader.varint()?,
+            },
+            from: reader.bytes()?,
+            amount: E8s {
+                e8s: reader.varint()?,
+            },
+            spender: reader.opt_bytes("spender")?,
+        }),
+        tag => {
+            return Err(DecodeError::InvalidTag {
+                field: "operation",
+                tag,
+            })
+        }
+    };
+    Ok(Some(operation))
+}
+
+pub fn encode_transaction(transaction: &StoredTransactions) -> Vec<u8> {
+    let mut writer = Writer {
+        bytes: Vec::with_capacity(160),
+    };
+
+    writer.varint(transaction.index);
+    writer.varint(transaction.memo);
+    writer.opt_bytes(transaction.icrc1_memo.as_deref());
+    write_operation(&mut writer, &transaction.operation);
+    writer.varint(transaction.created_at_time.timestamp_nanos);
+    writer.u8(match transaction.sweep_status {
+        SweepStatus::NotSwept => 0,
+        SweepStatus::Swept => 1,
+        SweepStatus::FailedToSweep => 2,
+        SweepStatus::Refunded => 3,
+    });
+    writer.u8(match transaction.flag {
+        None => 0,
+        Some(TransactionFlag::SubaccountDisabled) => 1,
+        Some(TransactionFlag::SubaccountArchived) => 2,
+        Some(TransactionFlag::WatchOnly) => 3,
+    });
+    writer.opt_varint(
+        transaction
+            .block_timestamp
+            .as_ref()
+            .map(|t| t.timestamp_nanos),
+    );
+    writer.opt_bytes(transaction.parent_hash.as_deref());
+    writer.opt_bytes(transaction.block_hash.as_deref());
+    writer.u8(match transaction.direction {
+        None => 0,
+        Some(TransactionDirection::Inbound) => 1,
+        Some(TransactionDirection::Outbound) => 2,
+        Some(TransactionDirection::Internal) => 3,
+    });
+
+    writer.bytes
+}
+
+pub fn decode_transaction(bytes: &[u8]) -> Result<StoredTransactions, DecodeError> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    let transaction = StoredTransactions {
+        index: reader.varint()?,
+        memo: reader.varint()?,
+        icrc1_memo: reader.opt_bytes("icrc1_memo")?,
+        operation: read_operation(&mut reader)?,
+        created_at_time: Timestamp::from_nanos(reader.varint()?),
+        sweep_status: match reader.u8()? {
+            0 => SweepStatus::NotSwept,
+            1 => SweepStatus::Swept,
+            2 => SweepStatus::FailedToSweep,
+            3 => SweepStatus::Refunded,
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    field: "sweep_status",
+                    tag,
+                })
+            }
+        },
+        flag: match reader.u8()? {
+            0 => None,
+            1 => Some(TransactionFlag::SubaccountDisabled),
+            2 => Some(TransactionFlag::SubaccountArchived),
+            3 => Some(TransactionFlag::WatchOnly),
+            tag => return Err(DecodeError::InvalidTag { field: "flag", tag }),
+        },
+        block_timestamp: reader
+            .opt_varint("block_timestamp")?
+            .map(Timestamp::from_nanos),
+        parent_hash: reader.opt_bytes("parent_hash")?,
+        block_hash: reader.opt_bytes("block_hash")?,
+        direction: match reader.u8()? {
+            0 => None,
+            1 => Some(TransactionDirection::Inbound),
+            2 => Some(TransactionDirection::Outbound),
+            3 => Some(TransactionDirection::Internal),
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    field: "direction",
+                    tag,
+                })
+            }
+        },
+    };
+
+    if reader.pos != bytes.len() {
+        return Err(DecodeError::TrailingBytes(bytes.len() - reader.pos));
+    }
+    Ok(transaction)
+}
@@ -0,0 +1,196 @@
+use candid::Principal;
+use serde::Serialize;
+
+use crate::address::format_icrc1_account;
+use crate::memory::{CUSTODIAN_PRINCIPAL, SUBACCOUNTS, WATCHED_ACCOUNTS};
+use crate::types::{Icrc1Account, Operation, StoredTransactions, TransactionDirection};
+use crate::{operation_amount, operation_kind, ToU64Hash, LIST_OF_SUBACCOUNTS, SUBACCOUNT_INDICES};
+
+// One exported transaction. Accounts are given as hex account identifiers, plus the ICRC-1
+// textual form where we know it, which is for our own subaccounts and watched accounts that
+// were imported in ICRC-1 form. Metadata describes our side of the transaction.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExportRow {
+    pub index: u64,
+    pub block_timestamp_nanos: u64,
+    pub created_at_time_nanos: u64,
+    pub operation: Option<String>,
+    pub direction: Option<String>,
+    pub amount_e8s: Option<u64>,
+    pub amount: Option<String>,
+    pub fee_e8s: Option<u64>,
+    pub memo: u64,
+    pub from: Option<String>,
+    pub from_icrc1: Option<String>,
+    pub to: Option<String>,
+    pub to_icrc1: Option<String>,
+    pub spender: Option<String>,
+    pub sweep_status: String,
+    pub flag: Option<String>,
+    pub subaccount_index: Option<u64>,
+    pub label: Option<String>,
+    pub external_id: Option<String>,
+    pub owner: Option<String>,
+}
+
+pub const CSV_HEADER: &str = "index,block_timestamp_nanos,created_at_time_nanos,operation,direction,amount_e8s,amount,fee_e8s,memo,from,from_icrc1,to,to_icrc1,spender,sweep_status,flag,subaccount_index,label,external_id,owner";
+
+#[derive(Default)]
+struct AccountDetails {
+    icrc1: Option<String>,
+    ours: bool,
+    subaccount_index: Option<u64>,
+    label: Option<String>,
+    external_id: Option<String>,
+    owner: Option<Principal>,
+}
+
+fn account_details(account: &[u8]) -> AccountDetails {
+    let account: [u8; 32] = match account.try_into() {
+        Ok(account) => account,
+        Err(_) => return AccountDetails::default(),
+    };
+    let hash = account.to_u64_hash();
+
+    let subaccount = LIST_OF_SUBACCOUNTS.with(|list_ref| list_ref.borrow().get(&hash).copied());
+    if let Some(subaccount) = subaccount {
+        let custodian = CUSTODIAN_PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().clone());
+        let icrc1 = custodian.get_principal().and_then(|owner| {
+            format_icrc1_account(&Icrc1Account {
+                owner,
+                subaccount: Some(subaccount.0.to_vec()),
+            })
+            .ok()
+        });
+        let index = SUBACCOUNT_INDICES.with(|indices_ref| indices_ref.borrow().get(&hash).copied());
+        let stored = index.and_then(|index| {
+            SUBACCOUNTS.with(|subaccounts_ref| subaccounts_ref.borrow().get(&index))
+        });
+
+        return AccountDetails {
+            icrc1,
+            ours: true,
+            subaccount_index: index,
+            label: stored.as_ref().and_then(|stored| stored.label.clone()),
+            external_id: stored
+                .as_ref()
+                .and_then(|stored| stored.external_id.clone()),
+            owner: stored.and_then(|stored| stored.owner),
+        };
+    }
+
+    match WATCHED_ACCOUNTS.with(|watched_ref| watched_ref.borrow().get(&account)) {
+        Some(watched) => AccountDetails {
+            icrc1: watched
+                .icrc1_account
+                .as_ref()
+                .and_then(|icrc1_account| format_icrc1_account(icrc1_account).ok()),
+            ours: true,
+            label: watched.label,
+            ..Default::default()
+        },
+        None => AccountDetails::default(),
+    }
+}
+
+// ICP amounts have 8 decimal places
+fn format_e8s(e8s: u64) -> String {
+    format!("{}.{:08}", e8s / 100_000_000, e8s % 100_000_000)
+}
+
+pub fn export_row(transaction: &StoredTransactions) -> ExportRow {
+    let (from, to, spender, fee_e8s) = match &transaction.operation {
+        Some(Operation::Approve(data)) => (
+            Some(&data.from),
+            None,
+            Some(&data.spender),
+            Some(data.fee.e8s),
+        ),
+        Some(Operation::Burn(data)) => (Some(&data.from), None, data.spender.as_ref(), None),
+        Some(Operation::Mint(data)) => (None, Some(&data.to), None, None),
+        Some(Operation::Transfer(data)) => (
+            Some(&data.from),
+            Some(&data.to),
+            data.spender.as_ref(),
+            Some(data.fee.e8s),
+        ),
+        None => (None, None, None, None),
+    };
+
+    let from_details = from.map(|from| account_details(from)).unwrap_or_default();
+    let to_details = to.map(|to| account_details(to)).unwrap_or_default();
+    let ours = match transaction.direction {
+        Some(TransactionDirection::Outbound) => &from_details,
+        _ if to_details.ours => &to_details,
+        _ => &from_details,
+    };
+    let amount_e8s = transaction.operation.as_ref().and_then(operation_amount);
+
+    ExportRow {
+        index: transaction.index,
+        block_timestamp_nanos: transaction.timestamp().timestamp_nanos,
+        created_at_time_nanos: transaction.created_at_time.timestamp_nanos,
+        operation: transaction
+            .operation
+            .as_ref()
+            .map(|operation| format!("{:?}", operation_kind(operation))),
+        direction: transaction
+            .direction
+            .as_ref()
+            .map(|direction| format!("{:?}", direction)),
+        amount_e8s,
+        amount: amount_e8s.map(format_e8s),
+        fee_e8s,
+        memo: transaction.memo,
+        from: from.map(hex::encode),
+        from_icrc1: from_details.icrc1.clone(),
+        to: to.map(hex::encode),
+        to_icrc1: to_details.icrc1.clone(),
+        spender: spender.map(hex::encode),
+        sweep_status: format!("{:?}", transaction.sweep_status),
+        flag: transaction.flag.as_ref().map(|flag| format!("{:?}", flag)),
+        subaccount_index: ours.subaccount_index,
+        label: ours.label.clone(),
+        external_id: ours.external_id.clone(),
+        owner: ours.owner.map(|owner| owner.to_text()),
+    }
+}
+
+fn csv_field(value: Option<String>) -> String {
+    let value = value.unwrap_or_default();
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+pub fn to_csv_line(row: &ExportRow) -> String {
+    let fields = [
+        Some(row.index.to_string()),
+        Some(row.block_timestamp_nanos.to_string()),
+        Some(row.created_at_time_nanos.to_string()),
+        row.operation.clone(),
+        row.direction.clone(),
+        row.amount_e8s.map(|e8s| e8s.to_string()),
+        row.amount.clone(),
+        row.fee_e8s.map(|e8s| e8s.to_string()),
+        Some(row.memo.to_string()),
+        row.from.clone(),
+        row.from_icrc1.clone(),
+        row.to.clone(),
+        row.to_icrc1.clone(),
+        row.spender.clone(),
+        Some(row.sweep_status.clone()),
+        row.flag.clone(),
+        row.subaccount_index.map(|index| index.to_string()),
+        row.label.clone(),
+        row.external_id.clone(),
+        row.owner.clone(),
+    ];
+    fields
+        .into_iter()
+        .map(csv_field)
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -0,0 +1,277 @@
+use candid::{CandidType, Deserialize};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::export::{export_row, ExportRow};
+use crate::memory::{
+    CUSTODIAN_PRINCIPAL, INTERVAL_IN_SECONDS, MIGRATION_STATE, NEXT_BLOCK, PRINCIPAL,
+    RETENTION_POLICY,
+};
+use crate::types::{
+    AccountQuery, AccountTransactionsRequest, ListSubaccountsRequest, RetentionPolicy, Timestamp,
+    TransactionFilter, TransactionsPage,
+};
+use crate::{
+    canister_status, collect_metrics, get_account_summary, get_totals, list_subaccounts,
+    list_transactions_for_account, query_transactions,
+};
+
+// Read-only JSON routes over the same queries as the Candid interface. Paged routes take
+// the Candid request fields as query parameters and return the same cursors.
+//
+//   GET /status
+//   GET /canister_status
+//   GET /metrics
+//   GET /totals
+//   GET /subaccounts?start_after=&limit=&label=&status=&has_unswept=
+//   GET /transactions?start_after=&limit=&direction=&from_block=&to_block=&from_timestamp=
+//       &to_timestamp=&operation=&sweep_status=&min_amount=&max_amount=&memo=
+//   GET /accounts/<address or index>/summary
+//   GET /accounts/<address or index>/transactions?start_after=&limit=&direction=
+//
+// Enum parameters use the variant names, e.g. direction=Descending; timestamps are nanoseconds.
+//
+// Responses carry no IC-Certificate header, so the certifying gateway at
+// <canister id>.icp0.io rejects them. Browsers reach the routes through the raw domain,
+// https://<canister id>.raw.icp0.io/status, which passes responses through unverified.
+// Clients that need verified data use get_certified_transactions instead.
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+struct HttpError {
+    status_code: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn bad_request(message: String) -> Self {
+        HttpError {
+            status_code: 400,
+            message,
+        }
+    }
+}
+
+impl From<crate::Error> for HttpError {
+    fn from(e: crate::Error) -> Self {
+        HttpError::bad_request(e.message)
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    ledger_principal: Option<String>,
+    custodian_principal: Option<String>,
+    next_block: u64,
+    interval_seconds: u64,
+    schema_version: u8,
+    migration_cursor: Option<u64>,
+    retention_policy: RetentionPolicy,
+}
+
+#[derive(Serialize)]
+struct TransactionRows {
+    transactions: Vec<ExportRow>,
+    next_cursor: Option<u64>,
+}
+
+impl From<TransactionsPage> for TransactionRows {
+    fn from(page: TransactionsPage) -> Self {
+        TransactionRows {
+            transactions: page.transactions.iter().map(export_row).collect(),
+            next_cursor: page.next_cursor,
+        }
+    }
+}
+
+fn percent_decode(text: &str) -> Result<String, HttpError> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = text
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| {
+                        HttpError::bad_request("Invalid percent-encoding".to_string())
+                    })?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| HttpError::bad_request("Invalid UTF-8".to_string()))
+}
+
+fn parse_query(query: &str) -> Result<HashMap<String, String>, HttpError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+struct Params(HashMap<String, String>);
+
+impl Params {
+    fn get<T: FromStr>(&self, name: &str) -> Result<Option<T>, HttpError> {
+        match self.0.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| HttpError::bad_request(format!("Invalid value for {}", name))),
+            None => Ok(None),
+        }
+    }
+
+    // Unit enum variants by name, as in the Candid interface
+    fn variant<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, HttpError> {
+        match self.0.get(name) {
+            Some(value) => serde_json::from_value(serde_json::Value::String(value.clone()))
+                .map(Some)
+                .map_err(|_| HttpError::bad_request(format!("Invalid value for {}", name))),
+            None => Ok(None),
+        }
+    }
+
+    fn timestamp(&self, name: &str) -> Result<Option<Timestamp>, HttpError> {
+        Ok(self.get(name)?.map(Timestamp::from_nanos))
+    }
+}
+
+// A subaccount index, or any address form the Candid API accepts
+fn account_query(segment: &str) -> Result<AccountQuery, HttpError> {
+    let segment = percent_decode(segment)?;
+    Ok(match segment.parse::<u64>() {
+        Ok(index) => AccountQuery::Nonce(index),
+        Err(_) => AccountQuery::Address(segment),
+    })
+}
+
+fn status() -> Status {
+    let ledger = PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().clone());
+    let custodian = CUSTODIAN_PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().clone());
+    let migration = MIGRATION_STATE.with(|state_ref| state_ref.borrow().get().clone());
+    Status {
+        ledger_principal: ledger.get_principal().map(|principal| principal.to_text()),
+        custodian_principal: custodian
+            .get_principal()
+            .map(|principal| principal.to_text()),
+        next_block: NEXT_BLOCK.with(|next_block_ref| *next_block_ref.borrow().get()),
+        interval_seconds: INTERVAL_IN_SECONDS.with(|interval_ref| *interval_ref.borrow().get()),
+        schema_version: migration.schema_version,
+        migration_cursor: migration.cursor,
+        retention_policy: RETENTION_POLICY.with(|policy_ref| policy_ref.borrow().get().clone()),
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Result<Vec<u8>, HttpError> {
+    serde_json::to_vec(value).map_err(|e| HttpError {
+        status_code: 500,
+        message: e.to_string(),
+    })
+}
+
+fn route(path: &str, params: &Params) -> Result<Vec<u8>, HttpError> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["status"] => json(&status()),
+        ["canister_status"] => json(&canister_status()),
+        ["metrics"] => json(&collect_metrics()),
+        ["totals"] => json(&get_totals()),
+        ["subaccounts"] => json(&list_subaccounts(ListSubaccountsRequest {
+            start_after: params.get("start_after")?,
+            limit: params.get("limit")?,
+            label: params.get("label")?,
+            status: params.variant("status")?,
+            has_unswept: params.get("has_unswept")?,
+        })),
+        ["transactions"] => json(&TransactionRows::from(query_transactions(
+            TransactionFilter {
+                start_after: params.get("start_after")?,
+                limit: params.get("limit")?,
+                direction: params.variant("direction")?,
+                from_block: params.get("from_block")?,
+                to_block: params.get("to_block")?,
+                from_timestamp: params.timestamp("from_timestamp")?,
+                to_timestamp: params.timestamp("to_timestamp")?,
+                operation: params.variant("operation")?,
+                sweep_status: params.variant("sweep_status")?,
+                min_amount: params.get("min_amount")?,
+                max_amount: params.get("max_amount")?,
+                memo: params.get("memo")?,
+            },
+        ))),
+        ["accounts", account, "summary"] => json(&get_account_summary(account_query(account)?)?),
+        ["accounts", account, "transactions"] => json(&TransactionRows::from(
+            list_transactions_for_account(AccountTransactionsRequest {
+                account: account_query(account)?,
+                start_after: params.get("start_after")?,
+                limit: params.get("limit")?,
+                direction: params.variant("direction")?,
+            })?,
+        )),
+        _ => Err(HttpError {
+            status_code: 404,
+            message: "Not found".to_string(),
+        }),
+    }
+}
+
+pub fn handle(req: &HttpRequest) -> HttpResponse {
+    let (path, query) = req.url.split_once('?').unwrap_or((&req.url, ""));
+    let result = if req.method != "GET" {
+        Err(HttpError {
+            status_code: 405,
+            message: "Only GET is supported".to_string(),
+        })
+    } else {
+        parse_query(query).and_then(|params| route(path, &Params(params)))
+    };
+
+    let (status_code, body) = match result {
+        Ok(body) => (200, body),
+        Err(e) => (
+            e.status_code,
+            serde_json::json!({ "error": e.message })
+                .to_string()
+                .into_bytes(),
+        ),
+    };
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+    }
+}
@@ -1,35 +1,84 @@
 use candid::{CandidType, Deserialize, Principal};
 use core::future::Future;
 use ic_cdk::api::call::CallResult;
+use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse, TransformArgs};
 use ic_cdk_macros::*;
 use ic_cdk_timers::TimerId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{StableBTreeMap, Storable};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
-use std::collections::{hash_map::DefaultHasher, HashMap};
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-
+use std::ops::Bound;
+
+mod address;
+mod certification;
+mod chain;
+mod encoding;
+mod export;
+mod http;
 mod memory;
 mod tests;
 mod types;
+mod webhooks;
 
-use ic_ledger_types::{
-    AccountIdentifier, Subaccount,
-};
+use ic_ledger_types::{AccountIdentifier, Subaccount};
 
+use address::{
+    icrc1_to_account_identifier, parse_account_identifier, parse_address, parse_icrc1_account,
+    ParsedAddress,
+};
 use memory::{
-    CUSTODIAN_PRINCIPAL, INTERVAL_IN_SECONDS, LAST_SUBACCOUNT_NONCE, NEXT_BLOCK, PRINCIPAL,
-    TRANSACTIONS,
+    stable_memory_usage, ACCOUNT_SUMMARIES, ACCOUNT_TRANSACTIONS, AUDIT_LOG, BACKFILL_STATE,
+    CHAIN_TIP, CUSTODIAN_PRINCIPAL, DEPOSIT_REFERENCES, DERIVATION_SCHEME, EVENTS,
+    INTERVAL_IN_SECONDS, INVOICES, INVOICE_ADDRESSES, INVOICE_MEMOS, LAST_SUBACCOUNT_NONCE,
+    MEMO_ACCOUNTS, MEMO_REFERENCES, MIGRATION_STATE, NEXT_BLOCK, PRINCIPAL, RETENTION_POLICY,
+    REVIEW_QUEUE, SUBACCOUNTS, SUBSCRIBERS, SYNC_STATE, TOTALS, TRANSACTIONS, WATCHED_ACCOUNTS,
+    WEBHOOKS, WEBHOOK_SECRETS,
 };
 use types::{
-    IcCdkSpawnManager, IcCdkSpawnManagerTrait, Icrc1TransferRequest, Icrc1TransferResponse,
-    InterCanisterCallManager, InterCanisterCallManagerTrait, Operation, QueryBlocksRequest,
-    QueryBlocksResponse, StoredPrincipal, StoredTransactions, SweepStatus, TimerManager,
-    TimerManagerTrait, Timestamp, ToRecord,
+    AccountInput, AccountQuery, AccountSummary, AccountTransactionsRequest, AuditEvent,
+    AuditLogPage, AuditRecord, BackfillState, Block, CanisterStatus, CertifiedTransaction,
+    CertifiedTransactionsPage, ChainTip, ClearTransactionsResponse, CreateSubaccountRequest,
+    DerivationScheme, Event, EventKind, EventsPage, ExportChunk, ExportFormat, ExportRequest,
+    HttpOutcallManager, HttpOutcallManagerTrait, IcCdkSpawnManager, IcCdkSpawnManagerTrait,
+    Icrc1TransferRequest, Icrc1TransferResponse, InterCanisterCallManager,
+    InterCanisterCallManagerTrait, Invoice, InvoiceState, InvoicesPage, ListInvoicesRequest,
+    ListSubaccountsRequest, ListSubaccountsResponse, ListTransactionsRequest, MemoValue, Metrics,
+    MigrationState, Operation, OperationKind, QueryBlocksRequest, QueryBlocksResponse,
+    RetentionPolicy, SortDirection, StoredPrincipal, StoredSubaccount, StoredTransactions,
+    StoredWatchedAccount, SubaccountInfo, SubaccountStatus, Subscriber, SubscriberStatus,
+    SubscriptionFilter, SweepStatus, TimerManager, TimerManagerTrait, Timestamp, ToRecord,
+    Transaction, TransactionDirection, TransactionFilter, TransactionFlag, TransactionsPage,
+    WatchedAccount, Webhook, WebhookDelivery, TRANSACTION_SCHEMA_VERSION,
 };
 
 thread_local! {
     static LIST_OF_SUBACCOUNTS: RefCell<HashMap<u64, Subaccount>> = RefCell::default();
+    // account id hash -> subaccount index, to look up metadata for a matched account
+    static SUBACCOUNT_INDICES: RefCell<HashMap<u64, u64>> = RefCell::default();
+    // External accounts that are indexed but never swept or refunded
+    static WATCHED_ACCOUNT_HASHES: RefCell<HashSet<u64>> = RefCell::default();
     static TIMERS: RefCell<TimerId> = RefCell::default();
+    static RETENTION_TIMER: RefCell<Option<TimerId>> = RefCell::default();
+    // Where the next retention batch resumes; restarting from the oldest entry after an upgrade is harmless
+    static RETENTION_CURSOR: RefCell<Option<u64>> = RefCell::default();
+    // Entries pruned and kept so far in the current pass, for its audit record
+    static RETENTION_TOTALS: RefCell<(u64, u64)> = RefCell::default();
+    // The pending delivery round and when it is due, in nanoseconds
+    static DELIVERY_TIMER: RefCell<Option<(TimerId, u64)>> = RefCell::default();
+    // Webhooks with an outcall under way; lost on upgrade, which only means an earlier retry
+    static WEBHOOKS_IN_FLIGHT: RefCell<HashSet<u64>> = RefCell::default();
+    // Sweeps and refunds by block index whose ledger call has not returned. Replies to calls
+    // made before an upgrade never reach the new code, so this need not survive one.
+    static TRANSFERS_IN_FLIGHT: RefCell<HashMap<u64, SweepStatus>> = RefCell::default();
+    // Hashes of the stored transactions, rebuilt on upgrade; its root goes into the certified data
+    static CERTIFIED_TRANSACTIONS: RefCell<certification::TransactionTree> =
+        const { RefCell::new(certification::TransactionTree::new()) };
+    // Where the rebuild of the tree after an upgrade resumes, while it is under way
+    static CERTIFY_CURSOR: RefCell<Option<Option<u64>>> = const { RefCell::new(None) };
 }
 
 #[derive(Debug, CandidType, Deserialize, Serialize)]
@@ -75,7 +124,8 @@ fn includes_hash(vec_to_check: &Vec<u8>) -> bool {
                         ic_cdk::println!("hash_key: {}", hash_key);
                         match subaccounts_borrow.get(&hash_key) {
                             Some(_) => true,
-                            None => false,
+                            None => WATCHED_ACCOUNT_HASHES
+                                .with(|watched| watched.borrow().contains(&hash_key)),
                         }
                     })
                 }
@@ -89,11 +139,64 @@ fn includes_hash(vec_to_check: &Vec<u8>) -> bool {
     }
 }
 
+fn is_watch_only(vec_to_check: &[u8]) -> bool {
+    match <&[u8; 32]>::try_from(vec_to_check) {
+        Ok(array_ref) => WATCHED_ACCOUNT_HASHES
+            .with(|watched| watched.borrow().contains(&array_ref.to_u64_hash())),
+        Err(_) => false,
+    }
+}
+
+fn subaccount_status(vec_to_check: &[u8]) -> Option<SubaccountStatus> {
+    let array_ref: &[u8; 32] = vec_to_check.try_into().ok()?;
+    let index = SUBACCOUNT_INDICES
+        .with(|indices| indices.borrow().get(&array_ref.to_u64_hash()).copied())?;
+    SUBACCOUNTS
+        .with(|subaccounts_ref| subaccounts_ref.borrow().get(&index))
+        .map(|stored| stored.status)
+}
+
+// Deposits into subaccounts that are no longer active are kept for review instead of swept
+fn deposit_flag(operation: &Operation) -> Option<TransactionFlag> {
+    let to = match operation {
+        Operation::Transfer(data) => &data.to,
+        Operation::Mint(data) => &data.to,
+        _ => return None,
+    };
+
+    if is_watch_only(to) {
+        return Some(TransactionFlag::WatchOnly);
+    }
+
+    match subaccount_status(to)? {
+        SubaccountStatus::Active => None,
+        SubaccountStatus::Disabled => Some(TransactionFlag::SubaccountDisabled),
+        SubaccountStatus::Archived => Some(TransactionFlag::SubaccountArchived),
+    }
+}
+
+fn transaction_direction(operation: &Operation) -> Option<TransactionDirection> {
+    let (from_ours, to_ours) = match operation {
+        Operation::Transfer(data) => (includes_hash(&data.from), includes_hash(&data.to)),
+        Operation::Mint(data) => (false, includes_hash(&data.to)),
+        Operation::Burn(data) => (includes_hash(&data.from), false),
+        Operation::Approve(_) => return None,
+    };
+
+    match (from_ours, to_ours) {
+        (true, true) => Some(TransactionDirection::Internal),
+        (false, true) => Some(TransactionDirection::Inbound),
+        (true, false) => Some(TransactionDirection::Outbound),
+        (false, false) => None,
+    }
+}
+
 #[update]
 async fn set_next_block(block: u64) {
     NEXT_BLOCK.with(|next_block_ref| {
         let _ = next_block_ref.borrow_mut().set(block);
     });
+    update_certified_data();
 }
 
 #[query]
@@ -123,6 +226,51 @@ impl InterCanisterCallManagerTrait for InterCanisterCallManager {
     ) -> CallResult<(Icrc1TransferResponse,)> {
         ic_cdk::call(ledger_principal, "icrc1_transfer", (req,)).await
     }
+
+    fn notify_events(canister: Principal, method: &str, events: Vec<Event>) -> Result<(), String> {
+        ic_cdk::api::call::notify(canister, method, (events,)).map_err(|code| format!("{:?}", code))
+    }
+}
+
+// Generous for a batch of events on a 13-node subnet; unused cycles are refunded
+#[cfg(not(test))]
+const WEBHOOK_CYCLES: u128 = 2_000_000_000;
+#[cfg(not(test))]
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 8 * 1024;
+
+#[cfg(not(test))]
+impl HttpOutcallManagerTrait for HttpOutcallManager {
+    async fn post_json(
+        url: String,
+        headers: Vec<HttpHeader>,
+        body: Vec<u8>,
+    ) -> CallResult<(HttpResponse,)> {
+        use ic_cdk::api::management_canister::http_request::{
+            http_request, CanisterHttpRequestArgument, HttpMethod, TransformContext,
+        };
+
+        let request = CanisterHttpRequestArgument {
+            url,
+            max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+            method: HttpMethod::POST,
+            headers,
+            body: Some(body),
+            transform: Some(TransformContext::from_name(
+                "transform_webhook_response".to_string(),
+                vec![],
+            )),
+        };
+        http_request(request, WEBHOOK_CYCLES).await
+    }
+}
+
+// Replicas see different headers and bodies, so only the status goes through consensus
+#[query]
+fn transform_webhook_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        ..Default::default()
+    }
 }
 
 async fn call_query_blocks() {
@@ -149,83 +297,501 @@ async fn call_query_blocks() {
 
     let response = match call_result {
         Ok((response,)) => response,
-        Err(_) => {
+        Err((code, message)) => {
             ic_cdk::println!("query_blocks error occurred");
+            record_sync(Err(format!("query_blocks failed: {:?} {}", code, message)));
             return;
         }
     };
 
     ic_cdk::println!("Response: {:?}", response);
 
+    match ingest_blocks(next_block, &response) {
+        Ok(_) => record_sync(Ok(response.chain_length)),
+        Err(e) => {
+            ic_cdk::println!("Rejected query_blocks response: {}", e);
+            record_sync(Err(e));
+        }
+    }
+}
+
+// Takes the ledger's chain length on success
+fn record_sync(result: Result<u64, String>) {
+    SYNC_STATE.with(|state_ref| {
+        let mut state = state_ref.borrow().get().clone();
+        let now = Some(Timestamp::from_nanos(now_nanos()));
+        match result {
+            Ok(chain_length) => {
+                state.last_success_at = now;
+                state.consecutive_errors = 0;
+                state.chain_length = Some(chain_length);
+            }
+            Err(e) => {
+                state.last_failure_at = now;
+                state.last_error = Some(e);
+                state.consecutive_errors = state.consecutive_errors.saturating_add(1);
+            }
+        }
+        let _ = state_ref.borrow_mut().set(state);
+    });
+}
+
+// Stores the blocks only if they extend the verified chain; otherwise nothing advances and
+// the same range is fetched again next time. The ledger's certificate cannot be checked here:
+// query_blocks is called from a canister, so the call is replicated and the ledger has no
+// data certificate to return. The reply comes through consensus, and the hash chain ties
+// each batch to the blocks already stored.
+fn ingest_blocks(next_block: u64, response: &QueryBlocksResponse) -> Result<u64, String> {
+    if !response.blocks.is_empty() && response.first_block_index != next_block {
+        return Err(format!(
+            "Expected blocks from {} but got blocks from {}",
+            next_block, response.first_block_index
+        ));
+    }
+
+    let tip = CHAIN_TIP.with(|tip_ref| tip_ref.borrow().get().clone());
+    let tip = chain::verify_chain(&tip, next_block, &response.blocks)?;
+
+    let block_count = store_blocks(next_block, &response.blocks);
+    let _ = CHAIN_TIP.with(|tip_ref| tip_ref.borrow_mut().set(tip));
+    let _ = NEXT_BLOCK.with(|next_block_ref| next_block_ref.borrow_mut().set(block_count));
+    update_certified_data();
+    Ok(block_count)
+}
+
+#[query]
+fn get_chain_tip() -> Option<ChainTip> {
+    let tip = CHAIN_TIP.with(|tip_ref| tip_ref.borrow().get().clone());
+    if tip.hashes.is_empty() {
+        None
+    } else {
+        Some(tip)
+    }
+}
+
+// Drops the stored tip so the next batch is accepted without a link to the blocks before it.
+// The links within that batch and every later one are still checked. Controllers only.
+#[update]
+fn reset_chain_tip() -> Result<Option<ChainTip>, Error> {
+    require_controller()?;
+    let previous = get_chain_tip();
+    let _ = CHAIN_TIP.with(|tip_ref| tip_ref.borrow_mut().set(ChainTip::default()));
+    append_audit(AuditEvent::ChainTipReset {
+        caller: caller(),
+        previous: previous.clone(),
+    });
+    Ok(previous)
+}
+
+// Stores the blocks that touch our accounts and returns the index of the next block to fetch
+fn store_blocks(next_block: u64, blocks: &[Block]) -> u64 {
     let mut block_count = next_block;
-    response.blocks.iter().for_each(|block| {
-        block.transaction.operation.as_ref().map(|operation| {
-            ic_cdk::println!("Operation: {:?}", operation);
-
-            let subaccount_exist = match operation {
-                Operation::Approve(data) => {
-                    ic_cdk::println!("Approve detected");
-                    let from = data.from.clone();
-                    if includes_hash(&from) {
-                        true
-                    } else {
-                        let spender = data.spender.clone();
-                        includes_hash(&spender)
+    let block_hashes = chain::resolve_hashes(blocks);
+    blocks
+        .iter()
+        .zip(block_hashes)
+        .for_each(|(block, block_hash)| {
+            if let (Some(previous), Some(parent_hash)) =
+                (block_count.checked_sub(1), &block.parent_hash)
+            {
+                record_block_hash(previous, parent_hash);
+            }
+
+            block.transaction.operation.as_ref().map(|operation| {
+                ic_cdk::println!("Operation: {:?}", operation);
+
+                let subaccount_exist = match operation {
+                    Operation::Approve(data) => {
+                        ic_cdk::println!("Approve detected");
+                        let from = data.from.clone();
+                        if includes_hash(&from) {
+                            true
+                        } else {
+                            let spender = data.spender.clone();
+                            includes_hash(&spender)
+                        }
                     }
-                }
-                Operation::Burn(data) => {
-                    ic_cdk::println!("Burn detected");
-                    let from = data.from.clone();
-                    if includes_hash(&from) {
-                        true
-                    } else {
-                        match &data.spender {
-                            Some(spender) => includes_hash(&spender),
-                            None => false,
+                    Operation::Burn(data) => {
+                        ic_cdk::println!("Burn detected");
+                        let from = data.from.clone();
+                        if includes_hash(&from) {
+                            true
+                        } else {
+                            match &data.spender {
+                                Some(spender) => includes_hash(&spender),
+                                None => false,
+                            }
                         }
                     }
-                }
-                Operation::Mint(data) => {
-                    ic_cdk::println!("Mint detected");
-                    let to = data.to.clone();
-                    includes_hash(&to)
-                }
-                Operation::Transfer(data) => {
-                    ic_cdk::println!("Transfer detected");
-                    let to = data.to.clone();
-                    if includes_hash(&to) {
-                        true
-                    } else {
-                        match &data.spender {
-                            Some(spender) => includes_hash(&spender),
-                            None => false,
+                    Operation::Mint(data) => {
+                        ic_cdk::println!("Mint detected");
+                        let to = data.to.clone();
+                        includes_hash(&to)
+                    }
+                    Operation::Transfer(data) => {
+                        ic_cdk::println!("Transfer detected");
+                        let to = data.to.clone();
+                        let from = data.from.clone();
+                        if includes_hash(&to) || includes_hash(&from) {
+                            true
+                        } else {
+                            match &data.spender {
+                                Some(spender) => includes_hash(&spender),
+                                None => false,
+                            }
                         }
                     }
+                };
+
+                if subaccount_exist {
+                    ic_cdk::println!("Subaccount exists");
+                    TRANSACTIONS.with(|transactions_ref| {
+                        let mut transactions = transactions_ref.borrow_mut();
+
+                        let mut transaction =
+                            StoredTransactions::from_block(block_count, block.clone());
+                        transaction.flag = deposit_flag(operation);
+                        transaction.direction = transaction_direction(operation);
+                        transaction.block_hash = block_hash.clone();
+
+                        if !transactions.contains_key(&block_count) {
+                            // Filter keys that exist
+                            ic_cdk::println!("Inserting transaction");
+                            index_transaction(&transaction);
+                            aggregate_deposit(&transaction);
+                            if let Some((account, amount_e8s)) = deposit_amount(&transaction) {
+                                append_event(EventKind::DepositDetected {
+                                    block_index: block_count,
+                                    account: hex::encode(account),
+                                    amount_e8s,
+                                });
+                            }
+                            match_invoice(&transaction);
+                            attribute_deposit(&transaction);
+                            certify_transaction(&transaction);
+                            let _ = transactions.insert(block_count, transaction);
+                        } else {
+                            ic_cdk::println!("Transaction already exists");
+                        }
+                    });
                 }
-            };
+            });
+            block_count += 1;
+        });
+
+    block_count
+}
+
+// Settles the hash of a block stored with two candidates once its successor names one
+fn record_block_hash(index: u64, hash: &[u8]) {
+    TRANSACTIONS.with(|transactions_ref| {
+        let mut transactions = transactions_ref.borrow_mut();
+        let mut transaction = match transactions.get(&index) {
+            Some(transaction) if transaction.block_hash.is_none() => transaction,
+            _ => return,
+        };
+        let block = match transaction.block_timestamp.clone() {
+            Some(timestamp) => Block {
+                transaction: Transaction {
+                    memo: transaction.memo,
+                    icrc1_memo: transaction.icrc1_memo.clone(),
+                    operation: transaction.operation.clone(),
+                    created_at_time: transaction.created_at_time.clone(),
+                },
+                timestamp,
+                parent_hash: transaction.parent_hash.clone(),
+            },
+            None => return,
+        };
+        if chain::block_hashes(&block)
+            .iter()
+            .any(|candidate| candidate == hash)
+        {
+            transaction.block_hash = Some(hash.to_vec());
+            certify_transaction(&transaction);
+            transactions.insert(index, transaction);
+        }
+    });
+}
 
-            if subaccount_exist {
-                ic_cdk::println!("Subaccount exists");
-                TRANSACTIONS.with(|transactions_ref| {
-                    let mut transactions = transactions_ref.borrow_mut();
+// Every well-formed account an operation touches
+fn operation_accounts(operation: &Operation) -> Vec<[u8; 32]> {
+    let accounts: Vec<&Vec<u8>> = match operation {
+        Operation::Approve(data) => vec![&data.from, &data.spender],
+        Operation::Burn(data) => std::iter::once(&data.from)
+            .chain(data.spender.iter())
+            .collect(),
+        Operation::Mint(data) => vec![&data.to],
+        Operation::Transfer(data) => [&data.from, &data.to]
+            .into_iter()
+            .chain(data.spender.iter())
+            .collect(),
+    };
 
-                    let transaction =
-                        StoredTransactions::new(block_count, block.transaction.clone());
+    accounts
+        .into_iter()
+        .filter_map(|account| account.as_slice().try_into().ok())
+        .collect()
+}
 
-                    if !transactions.contains_key(&block_count) {
-                        // Filter keys that exist
-                        ic_cdk::println!("Inserting transaction");
-                        let _ = transactions.insert(block_count, transaction);
-                    } else {
-                        ic_cdk::println!("Transaction already exists");
-                    }
-                });
+fn index_transaction(transaction: &StoredTransactions) {
+    let operation = match &transaction.operation {
+        Some(operation) => operation,
+        None => return,
+    };
+
+    ACCOUNT_TRANSACTIONS.with(|index_ref| {
+        let mut index = index_ref.borrow_mut();
+        for account in operation_accounts(operation) {
+            if includes_hash(&account.to_vec()) {
+                index.insert((account, transaction.index), ());
             }
+        }
+    });
+}
+
+fn unindex_transaction(transaction: &StoredTransactions) {
+    let operation = match &transaction.operation {
+        Some(operation) => operation,
+        None => return,
+    };
+
+    ACCOUNT_TRANSACTIONS.with(|index_ref| {
+        let mut index = index_ref.borrow_mut();
+        for account in operation_accounts(operation) {
+            index.remove(&(account, transaction.index));
+        }
+    });
+}
+
+fn remove_transaction(key: u64) -> Option<StoredTransactions> {
+    let transaction =
+        TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow_mut().remove(&key));
+    if let Some(transaction) = &transaction {
+        unindex_transaction(transaction);
+        uncertify_transaction(key);
+        DEPOSIT_REFERENCES.with(|references_ref| references_ref.borrow_mut().remove(&key));
+        REVIEW_QUEUE.with(|queue_ref| queue_ref.borrow_mut().remove(&key));
+    }
+    transaction
+}
+
+// The receiving subaccount and amount, if the transaction is a deposit we custody
+fn deposit_amount(transaction: &StoredTransactions) -> Option<([u8; 32], u64)> {
+    if !transaction.is_inbound() || matches!(transaction.flag, Some(TransactionFlag::WatchOnly)) {
+        return None;
+    }
+    let (to, amount) = match &transaction.operation {
+        Some(Operation::Transfer(data)) => (&data.to, data.amount.e8s),
+        Some(Operation::Mint(data)) => (&data.to, data.amount.e8s),
+        _ => return None,
+    };
+    if !includes_hash(to) {
+        return None;
+    }
+    Some((to.as_slice().try_into().ok()?, amount))
+}
+
+// A deposit we still owe to either the custodian or the sender. Watch-only deposits and
+// entries that are not deposits into our accounts are never settled, so they never count.
+fn is_unsettled_deposit(transaction: &StoredTransactions) -> bool {
+    !transaction.is_settled() && deposit_amount(transaction).is_some()
+}
+
+// Deposits whose memo did not resolve are kept until someone attributes them
+fn awaiting_review(index: u64) -> bool {
+    REVIEW_QUEUE.with(|queue_ref| queue_ref.borrow().contains_key(&index))
+}
+
+fn update_aggregates(account: [u8; 32], update: impl Fn(&mut AccountSummary)) {
+    ACCOUNT_SUMMARIES.with(|summaries_ref| {
+        let mut summaries = summaries_ref.borrow_mut();
+        let mut summary = summaries.get(&account).unwrap_or_default();
+        update(&mut summary);
+        summaries.insert(account, summary);
+    });
+    TOTALS.with(|totals_ref| {
+        let mut totals = totals_ref.borrow().get().clone();
+        update(&mut totals);
+        let _ = totals_ref.borrow_mut().set(totals);
+    });
+}
+
+// Moves an amount between pending and the settled total for the status
+fn settle_amount(summary: &mut AccountSummary, status: &SweepStatus, amount: u64, add: bool) {
+    let total = match status {
+        SweepStatus::Swept => &mut summary.total_swept_e8s,
+        SweepStatus::Refunded => &mut summary.total_refunded_e8s,
+        SweepStatus::NotSwept | SweepStatus::FailedToSweep => return,
+    };
+    if add {
+        *total = total.saturating_add(amount);
+        summary.pending_e8s = summary.pending_e8s.saturating_sub(amount);
+    } else {
+        *total = total.saturating_sub(amount);
+        summary.pending_e8s = summary.pending_e8s.saturating_add(amount);
+    }
+}
+
+fn aggregate_deposit(transaction: &StoredTransactions) {
+    let (account, amount) = match deposit_amount(transaction) {
+        Some(deposit) => deposit,
+        None => return,
+    };
+    let timestamp = transaction.timestamp().clone();
+    update_aggregates(account, |summary| {
+        summary.deposit_count += 1;
+        summary.total_received_e8s = summary.total_received_e8s.saturating_add(amount);
+        summary.pending_e8s = summary.pending_e8s.saturating_add(amount);
+        settle_amount(summary, &transaction.sweep_status, amount, true);
+        summary.first_deposit_at = match summary.first_deposit_at.take() {
+            Some(first) if first.timestamp_nanos <= timestamp.timestamp_nanos => Some(first),
+            _ => Some(timestamp.clone()),
+        };
+        summary.last_deposit_at = match summary.last_deposit_at.take() {
+            Some(last) if last.timestamp_nanos >= timestamp.timestamp_nanos => Some(last),
+            _ => Some(timestamp.clone()),
+        };
+    });
+}
+
+// All sweep status changes go through here so the aggregates and the event log follow them
+fn change_sweep_status(transaction: &mut StoredTransactions, status: SweepStatus) {
+    let block_index = transaction.index;
+    match (&transaction.sweep_status, &status) {
+        (previous, next) if previous == next => {}
+        (_, SweepStatus::Swept) => append_event(EventKind::SweepPending { block_index }),
+        (_, SweepStatus::Refunded) => append_event(EventKind::RefundPending { block_index }),
+        (SweepStatus::Refunded, SweepStatus::FailedToSweep) => {
+            append_event(EventKind::RefundFailed { block_index })
+        }
+        (_, SweepStatus::FailedToSweep) => append_event(EventKind::SweepFailed { block_index }),
+        _ => {}
+    }
+
+    // A deposit the backfill has yet to reach is counted with its status at that point
+    if let (Some((account, amount)), false) =
+        (deposit_amount(transaction), awaiting_backfill(block_index))
+    {
+        let previous = transaction.sweep_status.clone();
+        update_aggregates(account, |summary| {
+            settle_amount(summary, &previous, amount, false);
+            settle_amount(summary, &status, amount, true);
         });
-        block_count += 1;
+    }
+    transaction.sweep_status = status;
+}
+
+#[cfg(not(test))]
+fn set_certified_data(data: &[u8]) {
+    ic_cdk::api::set_certified_data(data);
+}
+
+#[cfg(test)]
+thread_local! {
+    static CERTIFIED_DATA: RefCell<Vec<u8>> = RefCell::default();
+}
+
+#[cfg(test)]
+fn set_certified_data(data: &[u8]) {
+    CERTIFIED_DATA.with(|certified_ref| *certified_ref.borrow_mut() = data.to_vec());
+}
+
+#[cfg(not(test))]
+fn data_certificate() -> Option<Vec<u8>> {
+    ic_cdk::api::data_certificate()
+}
+
+#[cfg(test)]
+fn data_certificate() -> Option<Vec<u8>> {
+    None
+}
+
+fn certified_root() -> [u8; 32] {
+    let next_block = NEXT_BLOCK.with(|next_block_ref| *next_block_ref.borrow().get());
+    CERTIFIED_TRANSACTIONS.with(|tree_ref| certification::root_hash(next_block, &tree_ref.borrow()))
+}
+
+fn certified_tree_complete() -> bool {
+    CERTIFY_CURSOR.with(|cursor_ref| cursor_ref.borrow().is_none())
+}
+
+// The certified data stays empty until the tree holds every stored transaction again
+fn update_certified_data() {
+    if certified_tree_complete() {
+        set_certified_data(&certified_root());
+    }
+}
+
+// A certificate over a partial tree would not match, so none is returned while it is rebuilt
+fn current_certificate() -> Option<Vec<u8>> {
+    if certified_tree_complete() {
+        data_certificate()
+    } else {
+        None
+    }
+}
+
+// Must be called whenever a stored transaction is written
+fn certify_transaction(transaction: &StoredTransactions) {
+    CERTIFIED_TRANSACTIONS.with(|tree_ref| {
+        tree_ref.borrow_mut().insert(
+            transaction.index.to_be_bytes(),
+            certification::transaction_hash(transaction),
+        )
     });
+    update_certified_data();
+}
 
-    let _ = NEXT_BLOCK.with(|next_block_ref| next_block_ref.borrow_mut().set(block_count));
+fn uncertify_transaction(index: u64) {
+    CERTIFIED_TRANSACTIONS.with(|tree_ref| tree_ref.borrow_mut().delete(&index.to_be_bytes()));
+    update_certified_data();
+}
+
+// The tree lives on the heap, so it is rebuilt from the stored transactions after an upgrade.
+// Hashing them all would not fit in post_upgrade, so it happens in timer batches.
+fn rebuild_certified_tree() {
+    CERTIFY_CURSOR.with(|cursor_ref| cursor_ref.replace(Some(None)));
+    set_certified_data(&[]);
+    TimerManager::set_timer_once(std::time::Duration::ZERO, certify_batch);
+}
+
+// Transactions written meanwhile are certified as usual, so a batch only fills in the rest
+fn certify_batch() {
+    let cursor = match CERTIFY_CURSOR.with(|cursor_ref| *cursor_ref.borrow()) {
+        Some(cursor) => cursor,
+        None => return,
+    };
+
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<(u64, StoredTransactions)> = TRANSACTIONS.with(|transactions_ref| {
+        transactions_ref
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(MIGRATION_BATCH_SIZE)
+            .collect()
+    });
+    CERTIFIED_TRANSACTIONS.with(|tree_ref| {
+        let mut tree = tree_ref.borrow_mut();
+        for (key, transaction) in &batch {
+            tree.insert(
+                key.to_be_bytes(),
+                certification::transaction_hash(transaction),
+            );
+        }
+    });
+
+    if batch.len() < MIGRATION_BATCH_SIZE {
+        CERTIFY_CURSOR.with(|cursor_ref| cursor_ref.replace(None));
+        update_certified_data();
+    } else {
+        let cursor = batch.last().map(|(key, _)| *key);
+        CERTIFY_CURSOR.with(|cursor_ref| cursor_ref.replace(Some(cursor)));
+        TimerManager::set_timer_once(std::time::Duration::ZERO, certify_batch);
+    }
 }
 
 async fn call_icrc1_transfer(ledger_principal: Principal, req: Icrc1TransferRequest) {
@@ -243,6 +809,36 @@ async fn call_icrc1_transfer(ledger_principal: Principal, req: Icrc1TransferRequ
     };
 
     ic_cdk::println!("Response: {:?}", response);
+    match response {
+        Icrc1TransferResponse::Ok(ledger_block) => icrc1_transfer_succeeded(req, ledger_block),
+        Icrc1TransferResponse::Err(_) => icrc1_transfer_error_handling(req),
+    }
+}
+
+fn icrc1_transfer_succeeded(req: Icrc1TransferRequest, ledger_block: u64) {
+    let block_index = match req.memo {
+        Some(memo) => vec_u8_to_u64(memo),
+        None => return,
+    };
+    TRANSFERS_IN_FLIGHT.with(|in_flight_ref| in_flight_ref.borrow_mut().remove(&block_index));
+    let status = TRANSACTIONS.with(|transactions_ref| {
+        transactions_ref
+            .borrow()
+            .get(&block_index)
+            .map(|transaction| transaction.sweep_status)
+    });
+
+    match status {
+        Some(SweepStatus::Swept) => append_event(EventKind::SweepSucceeded {
+            block_index,
+            ledger_block,
+        }),
+        Some(SweepStatus::Refunded) => append_event(EventKind::RefundSucceeded {
+            block_index,
+            ledger_block,
+        }),
+        _ => {}
+    }
 }
 
 fn vec_u8_to_u64(bytes: Vec<u8>) -> u64 {
@@ -261,6 +857,7 @@ fn icrc1_transfer_error_handling(req: Icrc1TransferRequest) {
 
     let _ = TRANSACTIONS.with(|transactions_ref| {
         let key = vec_u8_to_u64(memo);
+        TRANSFERS_IN_FLIGHT.with(|in_flight_ref| in_flight_ref.borrow_mut().remove(&key));
         let mut transaction = match { transactions_ref.borrow().get(&key).clone() } {
             Some(transaction) => transaction,
             None => {
@@ -268,7 +865,8 @@ fn icrc1_transfer_error_handling(req: Icrc1TransferRequest) {
             }
         };
 
-        transaction.sweep_status = SweepStatus::FailedToSweep;
+        change_sweep_status(&mut transaction, SweepStatus::FailedToSweep);
+        certify_transaction(&transaction);
 
         let mut transaction_borrow_mut = transactions_ref.borrow_mut();
         transaction_borrow_mut.remove(&key);
@@ -285,17 +883,35 @@ impl TimerManagerTrait for TimerManager {
         })
     }
 
+    fn set_timer_once(delay: std::time::Duration, task: fn()) -> TimerId {
+        ic_cdk_timers::set_timer(delay, task)
+    }
+
     fn clear_timer(timer_id: TimerId) {
         ic_cdk_timers::clear_timer(timer_id);
     }
 }
 
 #[ic_cdk::init]
-async fn init(seconds: u64, nonce: u32, ledger_principal: String, custodian_principal: String) {
+async fn init(
+    seconds: u64,
+    nonce: u32,
+    ledger_principal: String,
+    custodian_principal: String,
+    derivation_scheme: Option<DerivationScheme>,
+) {
     INTERVAL_IN_SECONDS.with(|interval_ref| {
         let _ = interval_ref.borrow_mut().set(seconds);
     });
 
+    // Addresses cannot be reconstructed after an upgrade without the scheme
+    DERIVATION_SCHEME.with(|scheme_ref| {
+        scheme_ref
+            .borrow_mut()
+            .set(derivation_scheme.unwrap_or_default())
+            .expect("Storing the derivation scheme failed");
+    });
+
     LAST_SUBACCOUNT_NONCE.with(|nonce_ref| {
         let _ = nonce_ref.borrow_mut().set(nonce);
     });
@@ -322,237 +938,2293 @@ async fn init(seconds: u64, nonce: u32, ledger_principal: String, custodian_prin
         timers_ref.replace(timer_id);
     });
 
+    schedule_retention(RETENTION_INTERVAL);
+
+    // A fresh install has nothing to migrate
+    MIGRATION_STATE.with(|state_ref| {
+        let _ = state_ref.borrow_mut().set(MigrationState {
+            schema_version: TRANSACTION_SCHEMA_VERSION,
+            cursor: None,
+        });
+    });
+
+    BACKFILL_STATE.with(|state_ref| {
+        let _ = state_ref.borrow_mut().set(BackfillState {
+            finished: true,
+            ..Default::default()
+        });
+    });
+
     reconstruct_subaccounts();
+    update_certified_data();
 }
 
 fn reconstruct_subaccounts() {
-    let nonce: u32 = get_nonce();
     let account = CUSTODIAN_PRINCIPAL
         .with(|stored_ref| stored_ref.borrow().get().clone())
         .get_principal()
         .expect("Custodian principal is not set");
 
     ic_cdk::println!("Reconstructing subaccounts for account: {:?}", account);
-    for i in 0..nonce {
-        ic_cdk::println!("nonce: {}", i);
-        let subaccount = to_subaccount(i);
-        let subaccountid: AccountIdentifier = to_subaccount_id(subaccount.clone()); 
-        let account_id_hash = subaccountid.to_u64_hash();
-        LIST_OF_SUBACCOUNTS.with(|list_ref| {
-            list_ref.borrow_mut().insert(account_id_hash, subaccount);
+
+    if get_derivation_scheme() == DerivationScheme::Nonce32 {
+        // Subaccounts created before the registry existed have no metadata yet
+        let nonce: u32 = get_nonce();
+        SUBACCOUNTS.with(|subaccounts_ref| {
+            let mut subaccounts = subaccounts_ref.borrow_mut();
+            for i in 0..nonce as u64 {
+                if !subaccounts.contains_key(&i) {
+                    subaccounts.insert(i, StoredSubaccount::new(None));
+                }
+            }
         });
     }
+
+    SUBACCOUNTS.with(|subaccounts_ref| {
+        subaccounts_ref.borrow().iter().for_each(|(index, stored)| {
+            match derive_subaccount(index, &stored) {
+                Ok(subaccount) => track_subaccount(index, subaccount),
+                Err(e) => ic_cdk::println!("Cannot derive subaccount {}: {}", index, e.message),
+            }
+        });
+    });
+}
+
+fn reconstruct_watched_accounts() {
+    WATCHED_ACCOUNTS.with(|watched_ref| {
+        watched_ref.borrow().iter().for_each(|(account_id, _)| {
+            WATCHED_ACCOUNT_HASHES.with(|hashes_ref| {
+                hashes_ref.borrow_mut().insert(account_id.to_u64_hash());
+            });
+        });
+    });
 }
 
 #[ic_cdk::post_upgrade]
 async fn post_upgrade() {
     ic_cdk::println!("running post_upgrade...");
     reconstruct_subaccounts();
+    reconstruct_watched_accounts();
+    start_backfill();
+    rebuild_certified_tree();
+    start_migration();
+    schedule_retention(RETENTION_INTERVAL);
+    // Timers do not survive upgrades; pick up any outstanding deliveries
+    schedule_delivery(std::time::Duration::ZERO);
 }
 
-#[query]
-fn get_interval() -> Result<u64, Error> {
-    INTERVAL_IN_SECONDS.with(|interval_ref| Ok(*interval_ref.borrow().get()))
+// Transactions rewritten per timer tick, to stay well within the instruction limit
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+fn start_migration() {
+    let state = MIGRATION_STATE.with(|state_ref| state_ref.borrow().get().clone());
+    if state.schema_version < TRANSACTION_SCHEMA_VERSION {
+        ic_cdk::println!(
+            "Migrating transactions from schema version {} to {}",
+            state.schema_version,
+            TRANSACTION_SCHEMA_VERSION
+        );
+        TimerManager::set_timer_once(std::time::Duration::ZERO, migrate_transactions_batch);
+    }
 }
 
-#[update]
-fn set_interval(seconds: u64) -> Result<u64, Error> {
-    TIMERS.with(|timers_ref| {
-        TimerManager::clear_timer(timers_ref.borrow().clone());
-    });
-
-    let interval = std::time::Duration::from_secs(seconds);
-    let new_timer_id = TimerManager::set_timer(interval);
+// Reading decodes any known version and writing always uses the current one, so a record is
+// migrated by re-inserting it. The cursor is kept in stable memory to survive another upgrade.
+fn migrate_transactions_batch() {
+    let mut state = MIGRATION_STATE.with(|state_ref| state_ref.borrow().get().clone());
+    if state.schema_version >= TRANSACTION_SCHEMA_VERSION {
+        return;
+    }
 
-    TIMERS.with(|timers_ref| {
-        timers_ref.replace(new_timer_id);
+    let batch: Vec<(u64, StoredTransactions)> = TRANSACTIONS.with(|transactions_ref| {
+        let mut transactions = transactions_ref.borrow_mut();
+        let start = match state.cursor {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let batch: Vec<(u64, StoredTransactions)> = transactions
+            .range((start, Bound::Unbounded))
+            .take(MIGRATION_BATCH_SIZE)
+            .collect();
+        for (key, transaction) in &batch {
+            transactions.insert(*key, transaction.clone());
+        }
+        batch
     });
 
-    INTERVAL_IN_SECONDS.with(|seconds_ref| {
-        let _ = seconds_ref.borrow_mut().set(seconds);
+    if batch.len() < MIGRATION_BATCH_SIZE {
+        state = MigrationState {
+            schema_version: TRANSACTION_SCHEMA_VERSION,
+            cursor: None,
+        };
+        ic_cdk::println!("Transaction migration finished");
+    } else {
+        state.cursor = batch.last().map(|(key, _)| *key);
+    }
+
+    let done = state.schema_version >= TRANSACTION_SCHEMA_VERSION;
+    MIGRATION_STATE.with(|state_ref| {
+        let _ = state_ref.borrow_mut().set(state);
     });
 
-    Ok(seconds)
+    if !done {
+        TimerManager::set_timer_once(std::time::Duration::ZERO, migrate_transactions_batch);
+    }
 }
 
-#[query]
-fn get_nonce() -> u32 {
-    LAST_SUBACCOUNT_NONCE.with(|nonce_ref| *nonce_ref.borrow().get())
-}
+// Decides on the first upgrade to a version with the account index and aggregates whether
+// stored history needs them, and resumes a pass an upgrade interrupted
+fn start_backfill() {
+    let mut state = BACKFILL_STATE.with(|state_ref| state_ref.borrow().get().clone());
+    if state.finished {
+        return;
+    }
 
-fn to_subaccount(nonce: u32) -> Subaccount {
-    let mut subaccount = Subaccount([0; 32]);
-    let nonce_bytes = nonce.to_be_bytes(); // Converts u32 to an array of 4 bytes
-    subaccount.0[32 - nonce_bytes.len()..].copy_from_slice(&nonce_bytes); // Aligns the bytes at the end of the array
-    subaccount
+    if state.end.is_none() {
+        state.account_index = ACCOUNT_TRANSACTIONS.with(|index_ref| index_ref.borrow().is_empty());
+        state.aggregates =
+            ACCOUNT_SUMMARIES.with(|summaries_ref| summaries_ref.borrow().is_empty());
+        state.end = TRANSACTIONS.with(|transactions_ref| {
+            transactions_ref
+                .borrow()
+                .last_key_value()
+                .map(|(key, _)| key)
+        });
+        state.finished = state.end.is_none() || !(state.account_index || state.aggregates);
+        BACKFILL_STATE.with(|state_ref| {
+            let _ = state_ref.borrow_mut().set(state.clone());
+        });
+    }
+
+    if !state.finished {
+        TimerManager::set_timer_once(std::time::Duration::ZERO, backfill_batch);
+    }
 }
 
-fn to_subaccount_id(subaccount: Subaccount) -> AccountIdentifier {
-    let account = CUSTODIAN_PRINCIPAL
-        .with(|stored_ref| stored_ref.borrow().get().clone())
-        .get_principal()
-        .expect("Custodian principal is not set");
-    AccountIdentifier::new(&account, &subaccount)
+// Whether the pass in progress has yet to aggregate the block
+fn awaiting_backfill(index: u64) -> bool {
+    let state = BACKFILL_STATE.with(|state_ref| state_ref.borrow().get().clone());
+    !state.finished
+        && state.aggregates
+        && state.end.is_some_and(|end| index <= end)
+        && match state.cursor {
+            Some(cursor) => index > cursor,
+            None => true,
+        }
 }
 
-fn from_hex(hex: &str) -> Result<[u8; 32], Error> {
-    
-    let vec = hex::decode(hex).map_err(|_| Error {
-        message: "string to vector conversion error".to_string(),
-    })?;
+// The state is saved with each batch, so no block is aggregated twice across an upgrade
+fn backfill_batch() {
+    let mut state = BACKFILL_STATE.with(|state_ref| state_ref.borrow().get().clone());
+    let end = match (state.finished, state.end) {
+        (false, Some(end)) => end,
+        _ => return,
+    };
 
-    let arr = vec.as_slice().try_into().map_err(|_| Error {
-        message: "vector to fix array conversion error".to_string(),
+    let start = match state.cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<(u64, StoredTransactions)> = TRANSACTIONS.with(|transactions_ref| {
+        transactions_ref
+            .borrow()
+            .range((start, Bound::Included(end)))
+            .take(MIGRATION_BATCH_SIZE)
+            .collect()
+    });
+    for (_key, transaction) in &batch {
+        if state.account_index {
+            index_transaction(transaction);
+        }
+        if state.aggregates {
+            aggregate_deposit(transaction);
+        }
+    }
+
+    if batch.len() < MIGRATION_BATCH_SIZE {
+        state.finished = true;
+        state.cursor = None;
+        ic_cdk::println!("Backfill finished");
+    } else {
+        state.cursor = batch.last().map(|(key, _)| *key);
+    }
+
+    let finished = state.finished;
+    BACKFILL_STATE.with(|state_ref| {
+        let _ = state_ref.borrow_mut().set(state);
+    });
+
+    if !finished {
+        TimerManager::set_timer_once(std::time::Duration::ZERO, backfill_batch);
+    }
+}
+
+#[query]
+fn get_migration_state() -> MigrationState {
+    MIGRATION_STATE.with(|state_ref| state_ref.borrow().get().clone())
+}
+
+#[cfg(not(test))]
+fn now_nanos() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+fn now_nanos() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+// Over a year of hourly retention passes; the oldest records go first
+const MAX_AUDIT_RECORDS: u64 = 10_000;
+
+fn append_audit(event: AuditEvent) {
+    AUDIT_LOG.with(|log_ref| {
+        let mut log = log_ref.borrow_mut();
+        let id = log.last_key_value().map_or(0, |(id, _)| id + 1);
+        log.insert(
+            id,
+            AuditRecord {
+                id,
+                timestamp: Timestamp::from_nanos(now_nanos()),
+                event,
+            },
+        );
+        while log.len() > MAX_AUDIT_RECORDS {
+            log.pop_first();
+        }
+    });
+}
+
+fn append_event(kind: EventKind) {
+    EVENTS.with(|events_ref| {
+        let mut events = events_ref.borrow_mut();
+        let seq = events.last_key_value().map_or(1, |(seq, _)| seq + 1);
+        events.insert(
+            seq,
+            Event {
+                seq,
+                timestamp: Timestamp::from_nanos(now_nanos()),
+                kind,
+            },
+        );
+    });
+
+    let has_subscribers = !SUBSCRIBERS.with(|subscribers_ref| subscribers_ref.borrow().is_empty())
+        || !WEBHOOKS.with(|webhooks_ref| webhooks_ref.borrow().is_empty());
+    if has_subscribers {
+        schedule_delivery(std::time::Duration::ZERO);
+    }
+}
+
+fn latest_event_seq() -> u64 {
+    EVENTS.with(|events_ref| {
+        events_ref
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(seq, _)| seq)
+    })
+}
+
+// Events after since_seq, oldest first. Consumers pass the last sequence number they
+// processed to resume.
+#[query]
+fn get_events(since_seq: Option<u64>, limit: Option<u64>) -> EventsPage {
+    let limit = limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+    let start = match since_seq {
+        Some(seq) => Bound::Excluded(seq),
+        None => Bound::Unbounded,
+    };
+
+    EVENTS.with(|events_ref| {
+        let events = events_ref.borrow();
+        EventsPage {
+            events: events
+                .range((start, Bound::Unbounded))
+                .take(limit)
+                .map(|(_, event)| event)
+                .collect(),
+            latest_seq: events.last_key_value().map_or(0, |(seq, _)| seq),
+        }
+    })
+}
+
+// Events per notification
+const DELIVERY_BATCH_SIZE: usize = 100;
+// How long a subscriber has to acknowledge a batch before it is sent again
+const ACK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+// Subscribers are paused after this many failed or unacknowledged deliveries in a row
+const MAX_DELIVERY_FAILURES: u32 = 5;
+// The indexer pays for every notification, so only a few canisters can subscribe
+const MAX_SUBSCRIBERS: u64 = 20;
+
+fn schedule_delivery(delay: std::time::Duration) {
+    let due = now_nanos().saturating_add(delay.as_nanos() as u64);
+    let scheduled = DELIVERY_TIMER.with(|timer_ref| *timer_ref.borrow());
+    if let Some((timer_id, scheduled_due)) = scheduled {
+        if scheduled_due <= due {
+            return;
+        }
+        TimerManager::clear_timer(timer_id);
+    }
+
+    let timer_id = TimerManager::set_timer_once(delay, deliver_events);
+    DELIVERY_TIMER.with(|timer_ref| timer_ref.replace(Some((timer_id, due))));
+}
+
+fn event_matches(event: &Event, filter: &SubscriptionFilter) -> bool {
+    if let Some(event_types) = &filter.event_types {
+        if !event_types.contains(&event.kind.event_type()) {
+            return false;
+        }
+    }
+
+    let deposit = event.kind.block_index().and_then(|block_index| {
+        TRANSACTIONS
+            .with(|transactions_ref| transactions_ref.borrow().get(&block_index))
+            .and_then(|transaction| deposit_amount(&transaction))
+    });
+    if let Some(accounts) = &filter.accounts {
+        let account = match &event.kind {
+            EventKind::DepositDetected { account, .. }
+            | EventKind::SubaccountCreated { account, .. } => Some(account.clone()),
+            _ => deposit.map(|(account, _)| hex::encode(account)),
+        };
+        if !account.is_some_and(|account| accounts.contains(&account)) {
+            return false;
+        }
+    }
+    if let Some(min_amount_e8s) = filter.min_amount_e8s {
+        let amount = match &event.kind {
+            EventKind::DepositDetected { amount_e8s, .. } => Some(*amount_e8s),
+            _ => deposit.map(|(_, amount)| amount),
+        };
+        if amount.is_some_and(|amount| amount < min_amount_e8s) {
+            return false;
+        }
+    }
+    true
+}
+
+// Accounts are stored in the lowercase hex form events carry
+fn normalize_filter(mut filter: SubscriptionFilter) -> Result<SubscriptionFilter, Error> {
+    if let Some(accounts) = filter.accounts.take() {
+        filter.accounts = Some(
+            accounts
+                .iter()
+                .map(|account| parse_account_identifier(account).map(hex::encode))
+                .collect::<Result<_, _>>()?,
+        );
+    }
+    Ok(filter)
+}
+
+// The next batch of matching events after a cursor, and how far the scan got
+fn pending_events(
+    after_seq: u64,
+    filter: &SubscriptionFilter,
+    batch_size: usize,
+) -> (Vec<Event>, u64) {
+    let mut batch = Vec::new();
+    let mut scanned_to = after_seq;
+    EVENTS.with(|events_ref| {
+        let events = events_ref.borrow();
+        for (scanned, (seq, event)) in events
+            .range((Bound::Excluded(after_seq), Bound::Unbounded))
+            .enumerate()
+        {
+            if batch.len() == batch_size || scanned == MAX_SCAN {
+                break;
+            }
+            scanned_to = seq;
+            if event_matches(&event, filter) {
+                batch.push(event);
+            }
+        }
+    });
+    (batch, scanned_to)
+}
+
+fn record_delivery_failure(subscriber: &mut Subscriber) {
+    subscriber.consecutive_failures += 1;
+    if subscriber.consecutive_failures >= MAX_DELIVERY_FAILURES {
+        ic_cdk::println!("Pausing subscriber {}", subscriber.canister);
+        subscriber.status = SubscriberStatus::Paused;
+    }
+}
+
+// Sends each active subscriber its next batch, or the unacknowledged one again after the
+// timeout. Runs again while any delivery is outstanding.
+fn deliver_events() {
+    DELIVERY_TIMER.with(|timer_ref| timer_ref.replace(None));
+    let now = now_nanos();
+    let subscribers: Vec<Subscriber> = SUBSCRIBERS.with(|subscribers_ref| {
+        subscribers_ref
+            .borrow()
+            .iter()
+            .map(|(_, subscriber)| subscriber)
+            .collect()
+    });
+
+    let mut outstanding = false;
+    for mut subscriber in subscribers {
+        if subscriber.status == SubscriberStatus::Paused {
+            continue;
+        }
+        if subscriber.sent_seq > subscriber.acked_seq {
+            let sent_at = subscriber
+                .last_sent_at
+                .as_ref()
+                .map_or(0, |sent_at| sent_at.timestamp_nanos);
+            if now < sent_at.saturating_add(ACK_TIMEOUT.as_nanos() as u64) {
+                outstanding = true;
+                continue;
+            }
+            subscriber.sent_seq = subscriber.acked_seq;
+            record_delivery_failure(&mut subscriber);
+        }
+
+        if subscriber.status == SubscriberStatus::Active {
+            let (batch, scanned_to) = pending_events(
+                subscriber.acked_seq,
+                &subscriber.filter,
+                DELIVERY_BATCH_SIZE,
+            );
+            match batch.last().map(|event| event.seq) {
+                // Nothing matched, so the subscriber has nothing to acknowledge up to here
+                None => {
+                    subscriber.acked_seq = scanned_to;
+                    subscriber.sent_seq = scanned_to;
+                }
+                Some(last_seq) => {
+                    match InterCanisterCallManager::notify_events(
+                        subscriber.canister,
+                        &subscriber.method,
+                        batch,
+                    ) {
+                        Ok(()) => {
+                            subscriber.sent_seq = last_seq;
+                            subscriber.last_sent_at = Some(Timestamp::from_nanos(now));
+                        }
+                        Err(e) => {
+                            ic_cdk::println!("Notifying {} failed: {}", subscriber.canister, e);
+                            record_delivery_failure(&mut subscriber);
+                        }
+                    }
+                    outstanding |= subscriber.status == SubscriberStatus::Active;
+                }
+            }
+        }
+
+        SUBSCRIBERS.with(|subscribers_ref| {
+            subscribers_ref
+                .borrow_mut()
+                .insert(subscriber.canister, subscriber)
+        });
+    }
+
+    let webhook_delay =
+        deliver_webhooks(now).map(|due| std::time::Duration::from_nanos(due.saturating_sub(now)));
+    let delay = match (outstanding.then_some(ACK_TIMEOUT), webhook_delay) {
+        (Some(ack), Some(webhook)) => Some(ack.min(webhook)),
+        (ack, webhook) => ack.or(webhook),
+    };
+    if let Some(delay) = delay {
+        schedule_delivery(delay);
+    }
+}
+
+// Events per webhook request
+const WEBHOOK_BATCH_SIZE: usize = 20;
+// Webhooks are paused after this many failed deliveries in a row
+const MAX_WEBHOOK_FAILURES: u32 = 10;
+// Every delivery pays for an outcall, so only a few endpoints can be registered
+const MAX_WEBHOOKS: u64 = 10;
+
+// Starts an outcall for each webhook that is due and has events waiting. Returns when the
+// earliest webhook backing off is next due.
+fn deliver_webhooks(now: u64) -> Option<u64> {
+    let webhooks: Vec<Webhook> = WEBHOOKS.with(|webhooks_ref| {
+        webhooks_ref
+            .borrow()
+            .iter()
+            .map(|(_, webhook)| webhook)
+            .collect()
+    });
+
+    let mut next_due: Option<u64> = None;
+    for mut webhook in webhooks {
+        let in_flight =
+            WEBHOOKS_IN_FLIGHT.with(|in_flight_ref| in_flight_ref.borrow().contains(&webhook.id));
+        if webhook.status == SubscriberStatus::Paused || in_flight {
+            continue;
+        }
+        if let Some(due) = webhook.next_attempt_at.as_ref().map(|t| t.timestamp_nanos) {
+            if due > now {
+                next_due = Some(next_due.map_or(due, |next_due| next_due.min(due)));
+                continue;
+            }
+        }
+
+        let (batch, scanned_to) =
+            pending_events(webhook.delivered_seq, &webhook.filter, WEBHOOK_BATCH_SIZE);
+        let (first_seq, last_seq) = match (batch.first(), batch.last()) {
+            (Some(first), Some(last)) => (first.seq, last.seq),
+            _ => {
+                // Nothing matched, so there is nothing to deliver up to here
+                if scanned_to > webhook.delivered_seq {
+                    webhook.delivered_seq = scanned_to;
+                    WEBHOOKS
+                        .with(|webhooks_ref| webhooks_ref.borrow_mut().insert(webhook.id, webhook));
+                }
+                continue;
+            }
+        };
+
+        let (headers, body) = webhook_request(webhook.id, &batch);
+        WEBHOOKS_IN_FLIGHT.with(|in_flight_ref| in_flight_ref.borrow_mut().insert(webhook.id));
+        IcCdkSpawnManager::run(post_webhook(
+            webhook.id,
+            webhook.url,
+            headers,
+            body,
+            first_seq,
+            last_seq,
+        ));
+    }
+    next_due
+}
+
+// A JSON body and headers signing it with the webhook's secret
+fn webhook_request(id: u64, batch: &[Event]) -> (Vec<HttpHeader>, Vec<u8>) {
+    let secret = WEBHOOK_SECRETS
+        .with(|secrets_ref| secrets_ref.borrow().get(&id))
+        .unwrap_or_default();
+    let body = webhooks::payload(id, batch);
+    let headers = vec![
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        HttpHeader {
+            name: webhooks::SIGNATURE_HEADER.to_string(),
+            value: webhooks::signature(&secret, &body),
+        },
+    ];
+    (headers, body)
+}
+
+async fn post_webhook(
+    id: u64,
+    url: String,
+    headers: Vec<HttpHeader>,
+    body: Vec<u8>,
+    first_seq: u64,
+    last_seq: u64,
+) {
+    let (http_status, error) = match HttpOutcallManager::post_json(url, headers, body).await {
+        Ok((response,)) => {
+            let status = u64::try_from(&response.status.0).unwrap_or_default();
+            let error = (!(200..300).contains(&status))
+                .then(|| format!("Webhook responded with status {}", status));
+            (Some(status), error)
+        }
+        Err((code, message)) => (None, Some(format!("{:?}: {}", code, message))),
+    };
+    record_webhook_result(id, first_seq, last_seq, http_status, error);
+}
+
+fn record_webhook_result(
+    id: u64,
+    first_seq: u64,
+    last_seq: u64,
+    http_status: Option<u64>,
+    error: Option<String>,
+) {
+    WEBHOOKS_IN_FLIGHT.with(|in_flight_ref| in_flight_ref.borrow_mut().remove(&id));
+    let mut webhook = match WEBHOOKS.with(|webhooks_ref| webhooks_ref.borrow().get(&id)) {
+        Some(webhook) => webhook,
+        None => return,
+    };
+
+    let now = now_nanos();
+    let delay = match &error {
+        None => {
+            webhook.delivered_seq = webhook.delivered_seq.max(last_seq);
+            webhook.consecutive_failures = 0;
+            webhook.next_attempt_at = None;
+            std::time::Duration::ZERO
+        }
+        Some(error) => {
+            ic_cdk::println!("Webhook {} delivery failed: {}", id, error);
+            webhook.consecutive_failures += 1;
+            let delay = webhooks::backoff(webhook.consecutive_failures);
+            webhook.next_attempt_at = Some(Timestamp::from_nanos(
+                now.saturating_add(delay.as_nanos() as u64),
+            ));
+            if webhook.consecutive_failures >= MAX_WEBHOOK_FAILURES {
+                webhook.status = SubscriberStatus::Paused;
+            }
+            delay
+        }
+    };
+    webhook.last_delivery = Some(WebhookDelivery {
+        first_seq,
+        last_seq,
+        attempted_at: Timestamp::from_nanos(now),
+        http_status,
+        error,
+    });
+
+    let active = webhook.status == SubscriberStatus::Active;
+    WEBHOOKS.with(|webhooks_ref| webhooks_ref.borrow_mut().insert(id, webhook));
+    if active {
+        schedule_delivery(delay);
+    }
+}
+
+// Webhooks start with the next new event. Each request carries an HMAC-SHA256 of its body,
+// keyed with the secret, in the X-Webhook-Signature header. Only controllers manage webhooks.
+#[update]
+fn add_webhook(url: String, filter: SubscriptionFilter, secret: Vec<u8>) -> Result<Webhook, Error> {
+    require_controller()?;
+    if WEBHOOKS.with(|webhooks_ref| webhooks_ref.borrow().len()) >= MAX_WEBHOOKS {
+        return Err(Error {
+            message: "Too many webhooks".to_string(),
+        });
+    }
+    if !url.starts_with("https://") {
+        return Err(Error {
+            message: "Webhook URL must use https".to_string(),
+        });
+    }
+    if secret.len() < 16 {
+        return Err(Error {
+            message: "Webhook secret must be at least 16 bytes".to_string(),
+        });
+    }
+
+    let id = WEBHOOKS.with(|webhooks_ref| {
+        webhooks_ref
+            .borrow()
+            .last_key_value()
+            .map_or(1, |(id, _)| id + 1)
+    });
+    let webhook = Webhook {
+        id,
+        url,
+        filter: normalize_filter(filter)?,
+        delivered_seq: latest_event_seq(),
+        status: SubscriberStatus::Active,
+        consecutive_failures: 0,
+        next_attempt_at: None,
+        last_delivery: None,
+    };
+    WEBHOOK_SECRETS.with(|secrets_ref| secrets_ref.borrow_mut().insert(id, secret));
+    WEBHOOKS.with(|webhooks_ref| webhooks_ref.borrow_mut().insert(id, webhook.clone()));
+    Ok(webhook)
+}
+
+#[update]
+fn remove_webhook(id: u64) -> Result<(), Error> {
+    require_controller()?;
+    WEBHOOK_SECRETS.with(|secrets_ref| secrets_ref.borrow_mut().remove(&id));
+    WEBHOOKS
+        .with(|webhooks_ref| webhooks_ref.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or(Error {
+            message: "Webhook not found".to_string(),
+        })
+}
+
+// Resuming retries right away
+#[update]
+fn set_webhook_status(id: u64, status: SubscriberStatus) -> Result<Webhook, Error> {
+    require_controller()?;
+    let webhook = WEBHOOKS.with(|webhooks_ref| {
+        let mut webhooks = webhooks_ref.borrow_mut();
+        let mut webhook = webhooks.get(&id).ok_or(Error {
+            message: "Webhook not found".to_string(),
+        })?;
+        if status == SubscriberStatus::Active {
+            webhook.consecutive_failures = 0;
+            webhook.next_attempt_at = None;
+        }
+        webhook.status = status;
+        webhooks.insert(id, webhook.clone());
+        Ok::<_, Error>(webhook)
+    })?;
+
+    schedule_delivery(std::time::Duration::ZERO);
+    Ok(webhook)
+}
+
+#[query]
+fn list_webhooks() -> Vec<Webhook> {
+    WEBHOOKS.with(|webhooks_ref| {
+        webhooks_ref
+            .borrow()
+            .iter()
+            .map(|(_, webhook)| webhook)
+            .collect()
+    })
+}
+
+// Subscriptions are managed by the subscribing canister itself or by a controller
+fn require_subscriber_or_controller(canister: &Principal) -> Result<(), Error> {
+    let caller = caller();
+    if caller == *canister || is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(Error {
+            message: "Caller is neither the subscriber nor a controller".to_string(),
+        })
+    }
+}
+
+// Registers or replaces a subscription. Delivery starts after since_seq, by default with the
+// next new event. The method receives a vec of events and must call ack_events.
+#[update]
+fn subscribe(
+    canister: Principal,
+    method: String,
+    filter: SubscriptionFilter,
+    since_seq: Option<u64>,
+) -> Result<Subscriber, Error> {
+    require_subscriber_or_controller(&canister)?;
+    // Candid ignores extra arguments, so the indexer's own methods would accept the events
+    if canister == own_principal() {
+        return Err(Error {
+            message: "The indexer cannot subscribe to itself".to_string(),
+        });
+    }
+    let full = SUBSCRIBERS.with(|subscribers_ref| {
+        let subscribers = subscribers_ref.borrow();
+        !subscribers.contains_key(&canister) && subscribers.len() >= MAX_SUBSCRIBERS
+    });
+    if full {
+        return Err(Error {
+            message: "Too many subscribers".to_string(),
+        });
+    }
+
+    let latest_seq = latest_event_seq();
+    let start = since_seq.unwrap_or(latest_seq);
+    if start > latest_seq {
+        return Err(Error {
+            message: "Sequence number is ahead of the event log".to_string(),
+        });
+    }
+    let filter = normalize_filter(filter)?;
+
+    let subscriber = Subscriber {
+        canister,
+        method,
+        filter,
+        acked_seq: start,
+        sent_seq: start,
+        last_sent_at: None,
+        consecutive_failures: 0,
+        status: SubscriberStatus::Active,
+    };
+    SUBSCRIBERS.with(|subscribers_ref| {
+        subscribers_ref
+            .borrow_mut()
+            .insert(canister, subscriber.clone())
+    });
+    schedule_delivery(std::time::Duration::ZERO);
+    Ok(subscriber)
+}
+
+#[update]
+fn unsubscribe(canister: Principal) -> Result<(), Error> {
+    require_subscriber_or_controller(&canister)?;
+    SUBSCRIBERS
+        .with(|subscribers_ref| subscribers_ref.borrow_mut().remove(&canister))
+        .map(|_| ())
+        .ok_or(Error {
+            message: "Subscriber not found".to_string(),
+        })
+}
+
+// Called by a subscriber with the last sequence number it processed
+#[update]
+fn ack_events(seq: u64) -> Result<(), Error> {
+    let canister = caller();
+    SUBSCRIBERS.with(|subscribers_ref| {
+        let mut subscribers = subscribers_ref.borrow_mut();
+        let mut subscriber = subscribers.get(&canister).ok_or(Error {
+            message: "Caller is not a subscriber".to_string(),
+        })?;
+        if seq > subscriber.sent_seq {
+            return Err(Error {
+                message: "Sequence number has not been delivered".to_string(),
+            });
+        }
+
+        subscriber.acked_seq = subscriber.acked_seq.max(seq);
+        subscriber.consecutive_failures = 0;
+        subscribers.insert(canister, subscriber);
+        Ok(())
+    })?;
+
+    schedule_delivery(std::time::Duration::ZERO);
+    Ok(())
+}
+
+// Resuming a paused subscriber sends everything after its last acknowledgement again
+#[update]
+fn set_subscriber_status(
+    canister: Principal,
+    status: SubscriberStatus,
+) -> Result<Subscriber, Error> {
+    require_subscriber_or_controller(&canister)?;
+    let subscriber = SUBSCRIBERS.with(|subscribers_ref| {
+        let mut subscribers = subscribers_ref.borrow_mut();
+        let mut subscriber = subscribers.get(&canister).ok_or(Error {
+            message: "Subscriber not found".to_string(),
+        })?;
+        if status == SubscriberStatus::Active {
+            subscriber.sent_seq = subscriber.acked_seq;
+            subscriber.consecutive_failures = 0;
+        }
+        subscriber.status = status;
+        subscribers.insert(canister, subscriber.clone());
+        Ok::<_, Error>(subscriber)
+    })?;
+
+    schedule_delivery(std::time::Duration::ZERO);
+    Ok(subscriber)
+}
+
+#[query]
+fn list_subscribers() -> Vec<Subscriber> {
+    SUBSCRIBERS.with(|subscribers_ref| {
+        subscribers_ref
+            .borrow()
+            .iter()
+            .map(|(_, subscriber)| subscriber)
+            .collect()
+    })
+}
+
+#[query]
+fn get_audit_log(start_after: Option<u64>, limit: Option<u64>) -> AuditLogPage {
+    let limit = limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+    let start = match start_after {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+
+    let mut records: Vec<AuditRecord> = AUDIT_LOG.with(|log_ref| {
+        log_ref
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit + 1)
+            .map(|(_, record)| record)
+            .collect()
+    });
+
+    let next_cursor = if records.len() > limit {
+        records.truncate(limit);
+        records.last().map(|record| record.id)
+    } else {
+        None
+    };
+
+    AuditLogPage {
+        records,
+        next_cursor,
+    }
+}
+
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// Entries examined per retention batch, to stay well within the instruction limit
+const RETENTION_BATCH_SIZE: usize = 1000;
+
+fn schedule_retention(delay: std::time::Duration) {
+    RETENTION_TIMER.with(|timer_ref| {
+        if let Some(timer_id) = timer_ref.borrow_mut().take() {
+            TimerManager::clear_timer(timer_id);
+        }
+    });
+
+    let policy = RETENTION_POLICY.with(|policy_ref| policy_ref.borrow().get().clone());
+    if !policy.is_active() {
+        return;
+    }
+
+    let timer_id = TimerManager::set_timer_once(delay, run_retention);
+    RETENTION_TIMER.with(|timer_ref| {
+        timer_ref.replace(Some(timer_id));
+    });
+}
+
+fn run_retention() {
+    let finished = prune_transactions(now_nanos());
+    // Keep going right away while there is a backlog
+    schedule_retention(if finished {
+        RETENTION_INTERVAL
+    } else {
+        std::time::Duration::ZERO
+    });
+}
+
+// Prunes one batch, oldest first. Returns whether the pass reached the end of the entries the
+// policy applies to.
+fn prune_transactions(now: u64) -> bool {
+    let policy = RETENTION_POLICY.with(|policy_ref| policy_ref.borrow().get().clone());
+    if !policy.is_active() {
+        return true;
+    }
+
+    let cutoff = policy
+        .max_age_seconds
+        .map(|seconds| now.saturating_sub(seconds.saturating_mul(1_000_000_000)));
+    let mut excess = match policy.max_count {
+        Some(max_count) => TRANSACTIONS
+            .with(|transactions_ref| transactions_ref.borrow().len())
+            .saturating_sub(max_count),
+        None => 0,
+    };
+
+    let start = match RETENTION_CURSOR.with(|cursor_ref| cursor_ref.borrow_mut().take()) {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<(u64, StoredTransactions)> = TRANSACTIONS.with(|transactions_ref| {
+        transactions_ref
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(RETENTION_BATCH_SIZE)
+            .collect()
+    });
+
+    let mut pruned = 0;
+    let mut kept_unsettled = 0;
+    let mut cursor = None;
+    for (position, (key, transaction)) in batch.iter().enumerate() {
+        let expired =
+            cutoff.is_some_and(|cutoff| transaction.timestamp().timestamp_nanos <= cutoff);
+        if !expired && excess == 0 {
+            break;
+        }
+
+        if is_unsettled_deposit(transaction)
+            || awaiting_review(*key)
+            || (policy.settled_only && !transaction.is_settled())
+        {
+            kept_unsettled += 1;
+        } else {
+            remove_transaction(*key);
+            pruned += 1;
+            excess = excess.saturating_sub(1);
+        }
+
+        if position + 1 == RETENTION_BATCH_SIZE {
+            cursor = Some(*key);
+        }
+    }
+
+    RETENTION_CURSOR.with(|cursor_ref| cursor_ref.replace(cursor));
+    let (pruned, kept_unsettled) = RETENTION_TOTALS.with(|totals_ref| {
+        let mut totals = totals_ref.borrow_mut();
+        *totals = (totals.0 + pruned, totals.1 + kept_unsettled);
+        *totals
+    });
+    // One record per pass, however many batches it took
+    if cursor.is_none() {
+        RETENTION_TOTALS.with(|totals_ref| totals_ref.replace((0, 0)));
+        append_audit(AuditEvent::RetentionRun {
+            pruned,
+            kept_unsettled,
+        });
+    }
+
+    cursor.is_none()
+}
+
+#[update]
+fn set_retention_policy(policy: RetentionPolicy) -> RetentionPolicy {
+    RETENTION_POLICY.with(|policy_ref| {
+        let _ = policy_ref.borrow_mut().set(policy.clone());
+    });
+    RETENTION_CURSOR.with(|cursor_ref| cursor_ref.replace(None));
+    RETENTION_TOTALS.with(|totals_ref| totals_ref.replace((0, 0)));
+    schedule_retention(std::time::Duration::ZERO);
+    policy
+}
+
+#[query]
+fn get_retention_policy() -> RetentionPolicy {
+    RETENTION_POLICY.with(|policy_ref| policy_ref.borrow().get().clone())
+}
+
+#[query]
+fn get_interval() -> Result<u64, Error> {
+    INTERVAL_IN_SECONDS.with(|interval_ref| Ok(*interval_ref.borrow().get()))
+}
+
+#[update]
+fn set_interval(seconds: u64) -> Result<u64, Error> {
+    TIMERS.with(|timers_ref| {
+        TimerManager::clear_timer(timers_ref.borrow().clone());
+    });
+
+    let interval = std::time::Duration::from_secs(seconds);
+    let new_timer_id = TimerManager::set_timer(interval);
+
+    TIMERS.with(|timers_ref| {
+        timers_ref.replace(new_timer_id);
+    });
+
+    INTERVAL_IN_SECONDS.with(|seconds_ref| {
+        let _ = seconds_ref.borrow_mut().set(seconds);
+    });
+
+    Ok(seconds)
+}
+
+#[query]
+fn get_nonce() -> u32 {
+    LAST_SUBACCOUNT_NONCE.with(|nonce_ref| *nonce_ref.borrow().get())
+}
+
+fn to_subaccount(nonce: u32) -> Subaccount {
+    let mut subaccount = Subaccount([0; 32]);
+    let nonce_bytes = nonce.to_be_bytes(); // Converts u32 to an array of 4 bytes
+    subaccount.0[32 - nonce_bytes.len()..].copy_from_slice(&nonce_bytes); // Aligns the bytes at the end of the array
+    subaccount
+}
+
+#[query]
+fn get_derivation_scheme() -> DerivationScheme {
+    DERIVATION_SCHEME.with(|scheme_ref| scheme_ref.borrow().get().clone())
+}
+
+fn derive_subaccount(index: u64, stored: &StoredSubaccount) -> Result<Subaccount, Error> {
+    match get_derivation_scheme() {
+        DerivationScheme::Nonce32 => {
+            let nonce = u32::try_from(index).map_err(|_| Error {
+                message: "Nonce is out of range".to_string(),
+            })?;
+            Ok(to_subaccount(nonce))
+        }
+        DerivationScheme::Counter64 => {
+            let mut subaccount = Subaccount([0; 32]);
+            subaccount.0[24..].copy_from_slice(&index.to_be_bytes());
+            Ok(subaccount)
+        }
+        DerivationScheme::Hashed { namespace } => {
+            let external_id = stored.external_id.as_ref().ok_or(Error {
+                message: "External id is required by the hashed scheme".to_string(),
+            })?;
+            let mut hasher = Sha256::new();
+            hasher.update((namespace.len() as u64).to_be_bytes());
+            hasher.update(namespace.as_bytes());
+            hasher.update(external_id.as_bytes());
+            Ok(Subaccount(hasher.finalize().into()))
+        }
+        DerivationScheme::PrincipalEmbedded => {
+            let owner = stored.owner.ok_or(Error {
+                message: "Owner is required by the principal-embedded scheme".to_string(),
+            })?;
+            let bytes = owner.as_slice();
+            let mut subaccount = Subaccount([0; 32]);
+            subaccount.0[0] = bytes.len() as u8;
+            subaccount.0[1..1 + bytes.len()].copy_from_slice(bytes);
+            Ok(subaccount)
+        }
+    }
+}
+
+fn track_subaccount(index: u64, subaccount: Subaccount) {
+    let account_id_hash = to_subaccount_id(subaccount).to_u64_hash();
+    LIST_OF_SUBACCOUNTS.with(|list_ref| {
+        list_ref.borrow_mut().insert(account_id_hash, subaccount);
+    });
+    SUBACCOUNT_INDICES.with(|indices_ref| {
+        indices_ref.borrow_mut().insert(account_id_hash, index);
+    });
+}
+
+fn to_subaccount_id(subaccount: Subaccount) -> AccountIdentifier {
+    let account = CUSTODIAN_PRINCIPAL
+        .with(|stored_ref| stored_ref.borrow().get().clone())
+        .get_principal()
+        .expect("Custodian principal is not set");
+    AccountIdentifier::new(&account, &subaccount)
+}
+
+fn from_hex(hex: &str) -> Result<[u8; 32], Error> {
+    let vec = hex::decode(hex).map_err(|_| Error {
+        message: "string to vector conversion error".to_string(),
+    })?;
+
+    let arr = vec.as_slice().try_into().map_err(|_| Error {
+        message: "vector to fix array conversion error".to_string(),
     })?;
 
-    Ok(arr)
+    Ok(arr)
+}
+
+fn new_subaccount(stored: StoredSubaccount) -> Result<String, Error> {
+    let scheme = get_derivation_scheme();
+    let index = match scheme {
+        DerivationScheme::Nonce32 => get_nonce() as u64,
+        _ => SUBACCOUNTS.with(|subaccounts_ref| {
+            subaccounts_ref
+                .borrow()
+                .last_key_value()
+                .map_or(0, |(index, _)| index + 1)
+        }),
+    };
+
+    let subaccount = derive_subaccount(index, &stored)?; // needed for storing the subaccount
+    let subaccountid: AccountIdentifier = to_subaccount_id(subaccount); // needed to get the hashkey & to return to user
+
+    // Hashed and principal-embedded addresses are deterministic, so a repeated request
+    // returns the existing address
+    let account_id_hash = subaccountid.to_u64_hash();
+    if LIST_OF_SUBACCOUNTS.with(|list_ref| list_ref.borrow().contains_key(&account_id_hash)) {
+        return Ok(subaccountid.to_hex());
+    }
+
+    track_subaccount(index, subaccount);
+    SUBACCOUNTS.with(|subaccounts_ref| {
+        subaccounts_ref.borrow_mut().insert(index, stored);
+    });
+    append_event(EventKind::SubaccountCreated {
+        index,
+        account: subaccountid.to_hex(),
+    });
+
+    if scheme == DerivationScheme::Nonce32 {
+        LAST_SUBACCOUNT_NONCE.with(|nonce_ref| {
+            let _ = nonce_ref.borrow_mut().set(index as u32 + 1);
+        });
+    }
+
+    Ok(subaccountid.to_hex())
+}
+
+#[update]
+fn add_subaccount(label: Option<String>) -> String {
+    match new_subaccount(StoredSubaccount::new(label)) {
+        Ok(subaccountid) => subaccountid,
+        Err(e) => ic_cdk::trap(&e.message),
+    }
+}
+
+#[update]
+fn create_subaccount(req: CreateSubaccountRequest) -> Result<String, Error> {
+    new_subaccount(StoredSubaccount {
+        label: req.label,
+        status: SubaccountStatus::Active,
+        external_id: req.external_id,
+        owner: req.owner,
+    })
+}
+
+#[query]
+fn get_subaccountid(nonce: u32) -> Result<String, Error> {
+    get_subaccountid_by_index(nonce as u64)
+}
+
+// As get_subaccountid, for indices past the nat32 range
+#[query]
+fn get_subaccountid_by_index(nonce: u64) -> Result<String, Error> {
+    let stored = match SUBACCOUNTS.with(|subaccounts_ref| subaccounts_ref.borrow().get(&nonce)) {
+        Some(stored) => stored,
+        None => {
+            return Err(Error {
+                message: "Index out of bounds".to_string(),
+            });
+        }
+    };
+
+    LIST_OF_SUBACCOUNTS.with(|subaccounts| {
+        let subaccounts_borrow = subaccounts.borrow();
+
+        let subaccount = derive_subaccount(nonce, &stored)?;
+        let subaccountid: AccountIdentifier = to_subaccount_id(subaccount);
+        let account_id_hash = subaccountid.to_u64_hash();
+
+        ic_cdk::println!("account_id_hash to search: {}", account_id_hash);
+
+        // find matching hashkey
+        match subaccounts_borrow.get(&account_id_hash) {
+            Some(_) => Ok(subaccountid.to_hex()),
+            None => Err(Error {
+                message: "Account not found".to_string(),
+            }),
+        }
+    })
+}
+
+#[update]
+fn set_subaccount_status(index: u64, status: SubaccountStatus) -> Result<SubaccountStatus, Error> {
+    SUBACCOUNTS.with(|subaccounts_ref| {
+        let mut subaccounts = subaccounts_ref.borrow_mut();
+        let mut stored = match subaccounts.get(&index) {
+            Some(stored) => stored,
+            None => {
+                return Err(Error {
+                    message: "Account not found".to_string(),
+                });
+            }
+        };
+
+        stored.status = status.clone();
+        subaccounts.insert(index, stored);
+        Ok(status)
+    })
+}
+
+#[update]
+fn watch_account(account: AccountInput, label: Option<String>) -> Result<String, Error> {
+    let ParsedAddress {
+        account_id,
+        icrc1_account,
+    } = match account {
+        AccountInput::AccountId(hex) => ParsedAddress {
+            account_id: parse_account_identifier(&hex)?,
+            icrc1_account: None,
+        },
+        AccountInput::Icrc1(icrc1_account) => ParsedAddress {
+            account_id: icrc1_to_account_identifier(&icrc1_account)?,
+            icrc1_account: Some(icrc1_account),
+        },
+        AccountInput::Text(text) => parse_address(&text)?,
+    };
+
+    let hash_key = account_id.to_u64_hash();
+    if LIST_OF_SUBACCOUNTS.with(|subaccounts| subaccounts.borrow().contains_key(&hash_key)) {
+        return Err(Error {
+            message: "Account is already managed by this canister".to_string(),
+        });
+    }
+
+    WATCHED_ACCOUNTS.with(|watched_ref| {
+        watched_ref.borrow_mut().insert(
+            account_id,
+            StoredWatchedAccount {
+                label,
+                icrc1_account,
+            },
+        );
+    });
+    WATCHED_ACCOUNT_HASHES.with(|hashes_ref| {
+        hashes_ref.borrow_mut().insert(hash_key);
+    });
+
+    Ok(hex::encode(account_id))
+}
+
+#[query]
+fn list_watched_accounts() -> Vec<WatchedAccount> {
+    WATCHED_ACCOUNTS.with(|watched_ref| {
+        watched_ref
+            .borrow()
+            .iter()
+            .map(|(account_id, stored)| WatchedAccount {
+                account_id: hex::encode(account_id),
+                label: stored.label,
+                icrc1_account: stored.icrc1_account,
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn get_subaccount_count() -> u32 {
+    LIST_OF_SUBACCOUNTS.with(|subaccounts| subaccounts.borrow().len() as u32)
+}
+
+// As with query_transactions, a filtered page may come back short or empty while
+// `next_cursor` is set, once the scan budget runs out.
+#[query]
+fn list_subaccounts(req: ListSubaccountsRequest) -> ListSubaccountsResponse {
+    let limit = req.limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+    let start = match req.start_after {
+        Some(cursor) => cursor.saturating_add(1),
+        None => 0,
+    };
+
+    let mut subaccounts = Vec::new();
+    let mut last_scanned = None;
+    let mut next_cursor = None;
+
+    SUBACCOUNTS.with(|subaccounts_ref| {
+        for (scanned, (key, stored)) in subaccounts_ref.borrow().range(start..).enumerate() {
+            if subaccounts.len() == limit || scanned == MAX_SCAN {
+                // There is at least one more entry past the page
+                next_cursor = last_scanned;
+                break;
+            }
+            last_scanned = Some(key);
+
+            if req.label.is_some() && stored.label != req.label {
+                continue;
+            }
+            if let Some(status) = &req.status {
+                if &stored.status != status {
+                    continue;
+                }
+            }
+
+            let subaccountid = match derive_subaccount(key, &stored) {
+                Ok(subaccount) => to_subaccount_id(subaccount),
+                Err(_) => continue,
+            };
+            let deposits = match from_hex(&subaccountid.to_hex()) {
+                Ok(account) => ACCOUNT_SUMMARIES
+                    .with(|summaries_ref| summaries_ref.borrow().get(&account))
+                    .unwrap_or_default(),
+                Err(_) => continue,
+            };
+
+            if let Some(has_unswept) = req.has_unswept {
+                if (deposits.pending_e8s > 0) != has_unswept {
+                    continue;
+                }
+            }
+
+            subaccounts.push(SubaccountInfo {
+                index: key,
+                subaccountid: subaccountid.to_hex(),
+                label: stored.label,
+                status: stored.status,
+                deposits,
+            });
+        }
+    });
+
+    ListSubaccountsResponse {
+        subaccounts,
+        next_cursor,
+    }
+}
+
+#[query]
+fn get_transactions_count() -> u32 {
+    TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow().len() as u32)
+}
+
+#[query]
+fn get_oldest_block() -> Option<u64> {
+    TRANSACTIONS.with(|transactions_ref| {
+        let transactions_borrow = transactions_ref.borrow();
+        transactions_borrow.iter().next().map(|(key, _value)| key)
+    })
+}
+
+// Upper bound on page sizes so a single reply stays well below the message limit
+const MAX_PAGE_SIZE: u64 = 1000;
+
+// Upper bound on entries examined by one filtered query, to stay within the instruction limit
+const MAX_SCAN: usize = 10_000;
+
+// StableBTreeMap only iterates forward, so walk backwards one key at a time. Each step is a
+// single tree descent, which keeps the cost of a page independent of the map size.
+fn range_descending<K, V>(
+    map: &StableBTreeMap<K, V, types::Memory>,
+    start_before: Option<K>,
+    floor: Option<&K>,
+    limit: usize,
+) -> Vec<(K, V)>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let mut result = Vec::new();
+    let mut next = match start_before {
+        Some(bound) => map.iter_upper_bound(&bound).next(),
+        None => map.last_key_value(),
+    };
+
+    while let Some((key, value)) = next {
+        if result.len() == limit || floor.is_some_and(|floor| &key < floor) {
+            break;
+        }
+        next = map.iter_upper_bound(&key).next();
+        result.push((key, value));
+    }
+    result
+}
+
+fn transactions_page(
+    start_after: Option<u64>,
+    limit: Option<u64>,
+    direction: SortDirection,
+) -> TransactionsPage {
+    let limit = limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+
+    TRANSACTIONS.with(|transactions_ref| {
+        let transactions_borrow = transactions_ref.borrow();
+
+        // Fetch one extra entry to find out whether another page follows
+        let mut entries: Vec<(u64, StoredTransactions)> = match direction {
+            SortDirection::Ascending => {
+                let range = match start_after {
+                    Some(cursor) => (Bound::Excluded(cursor), Bound::Unbounded),
+                    None => (Bound::Unbounded, Bound::Unbounded),
+                };
+                transactions_borrow.range(range).take(limit + 1).collect()
+            }
+            SortDirection::Descending => {
+                range_descending(&transactions_borrow, start_after, None, limit + 1)
+            }
+        };
+
+        let next_cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| *key)
+        } else {
+            None
+        };
+
+        TransactionsPage {
+            transactions: entries.into_iter().map(|(_, value)| value).collect(),
+            next_cursor,
+        }
+    })
+}
+
+fn operation_kind(operation: &Operation) -> OperationKind {
+    match operation {
+        Operation::Approve(_) => OperationKind::Approve,
+        Operation::Burn(_) => OperationKind::Burn,
+        Operation::Mint(_) => OperationKind::Mint,
+        Operation::Transfer(_) => OperationKind::Transfer,
+    }
+}
+
+fn operation_amount(operation: &Operation) -> Option<u64> {
+    match operation {
+        Operation::Approve(_) => None,
+        Operation::Burn(data) => Some(data.amount.e8s),
+        Operation::Mint(data) => Some(data.amount.e8s),
+        Operation::Transfer(data) => Some(data.amount.e8s),
+    }
+}
+
+fn matches_filter(transaction: &StoredTransactions, filter: &TransactionFilter) -> bool {
+    let timestamp = transaction.timestamp().timestamp_nanos;
+    if let Some(from) = &filter.from_timestamp {
+        if timestamp < from.timestamp_nanos {
+            return false;
+        }
+    }
+    if let Some(to) = &filter.to_timestamp {
+        if timestamp > to.timestamp_nanos {
+            return false;
+        }
+    }
+    if filter.memo.is_some_and(|memo| transaction.memo != memo) {
+        return false;
+    }
+    if let Some(sweep_status) = &filter.sweep_status {
+        if &transaction.sweep_status != sweep_status {
+            return false;
+        }
+    }
+    if let Some(kind) = &filter.operation {
+        match &transaction.operation {
+            Some(operation) if &operation_kind(operation) == kind => {}
+            _ => return false,
+        }
+    }
+    if filter.min_amount.is_some() || filter.max_amount.is_some() {
+        let amount = match transaction.operation.as_ref().and_then(operation_amount) {
+            Some(amount) => amount,
+            None => return false,
+        };
+        if filter.min_amount.is_some_and(|min| amount < min)
+            || filter.max_amount.is_some_and(|max| amount > max)
+        {
+            return false;
+        }
+    }
+    true
+}
+
+// Keeps an export chunk well below the reply size limit
+const MAX_EXPORT_BYTES: usize = 1_000_000;
+
+#[query]
+fn export_transactions(req: ExportRequest) -> ExportChunk {
+    let first_chunk = req.filter.start_after.is_none();
+    let mut filter = req.filter;
+    filter.limit = Some(filter.limit.unwrap_or(MAX_PAGE_SIZE));
+    let page = query_transactions(filter);
+
+    let mut lines = Vec::new();
+    let mut size = 0;
+    let mut next_cursor = page.next_cursor;
+    for transaction in &page.transactions {
+        let row = export::export_row(transaction);
+        let line = match req.format {
+            ExportFormat::Json => serde_json::to_string(&row).expect("Serializing row failed"),
+            ExportFormat::Csv => export::to_csv_line(&row),
+        };
+        if !lines.is_empty() && size + line.len() > MAX_EXPORT_BYTES {
+            // Resume after the last row that fit
+            next_cursor = lines.last().map(|(index, _)| *index);
+            break;
+        }
+        size += line.len() + 1;
+        lines.push((transaction.index, line));
+    }
+
+    let rows = lines.len() as u64;
+    let lines: Vec<String> = lines.into_iter().map(|(_, line)| line).collect();
+    let data = match req.format {
+        ExportFormat::Json => format!("[{}]", lines.join(",")),
+        ExportFormat::Csv => {
+            let mut data = String::new();
+            if first_chunk {
+                data.push_str(export::CSV_HEADER);
+                data.push('\n');
+            }
+            for line in lines {
+                data.push_str(&line);
+                data.push('\n');
+            }
+            data
+        }
+    };
+
+    ExportChunk {
+        data,
+        rows,
+        next_cursor,
+    }
+}
+
+#[query]
+fn list_transactions(up_to_count: Option<u64>) -> Vec<StoredTransactions> {
+    // process argument
+    let up_to_count = match up_to_count {
+        Some(count) => count,
+        None => 100, // Default is 100
+    };
+
+    // newest `up_to_count` transactions, returned oldest first
+    let mut result = TRANSACTIONS.with(|transactions_ref| {
+        range_descending(&transactions_ref.borrow(), None, None, up_to_count as usize)
+    });
+    result.reverse();
+    result.into_iter().map(|(_key, value)| value).collect()
+}
+
+#[query]
+fn list_transactions_page(req: ListTransactionsRequest) -> TransactionsPage {
+    transactions_page(
+        req.start_after,
+        req.limit,
+        req.direction.unwrap_or(SortDirection::Ascending),
+    )
+}
+
+#[query]
+fn get_certified_transactions(req: ListTransactionsRequest) -> CertifiedTransactionsPage {
+    let page = list_transactions_page(req);
+    let next_block = NEXT_BLOCK.with(|next_block_ref| *next_block_ref.borrow().get());
+    let indices = page
+        .transactions
+        .iter()
+        .map(|transaction| transaction.index);
+    let witness = CERTIFIED_TRANSACTIONS.with(|tree_ref| {
+        let tree = tree_ref.borrow();
+        match (indices.clone().min(), indices.max()) {
+            (Some(first), Some(last)) => {
+                certification::range_witness(next_block, &tree, first, last)
+            }
+            _ => certification::cursor_witness(next_block, &tree),
+        }
+    });
+
+    CertifiedTransactionsPage {
+        page,
+        certificate: current_certificate(),
+        witness: certification::encode_witness(&witness),
+    }
+}
+
+// The witness holds the transaction's leaf, sha256(block_hash || sweep_status || flag) as laid
+// out in the certification module; check the transaction's ledger fields against block_hash.
+#[query]
+fn get_certified_transaction(block_index: u64) -> CertifiedTransaction {
+    let next_block = NEXT_BLOCK.with(|next_block_ref| *next_block_ref.borrow().get());
+    let witness = CERTIFIED_TRANSACTIONS
+        .with(|tree_ref| certification::witness(next_block, &tree_ref.borrow(), block_index));
+
+    CertifiedTransaction {
+        transaction: TRANSACTIONS
+            .with(|transactions_ref| transactions_ref.borrow().get(&block_index)),
+        certificate: current_certificate(),
+        witness: certification::encode_witness(&witness),
+    }
+}
+
+// The cursor is the last block examined, so a page may come back short or even empty
+// while `next_cursor` is still set when the scan budget ran out before the range did.
+#[query]
+fn query_transactions(filter: TransactionFilter) -> TransactionsPage {
+    let limit = filter.limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+    let direction = filter.direction.unwrap_or(SortDirection::Ascending);
+
+    // Narrow the block range to whatever lies past the cursor
+    let from_block = filter.from_block.unwrap_or(0);
+    let to_block = filter.to_block.unwrap_or(u64::MAX);
+    let (lower, upper) = match (&direction, filter.start_after) {
+        (SortDirection::Ascending, Some(cursor)) => (
+            cursor.checked_add(1).map(|start| start.max(from_block)),
+            Some(to_block),
+        ),
+        (SortDirection::Descending, Some(cursor)) => (
+            Some(from_block),
+            cursor.checked_sub(1).map(|end| end.min(to_block)),
+        ),
+        _ => (Some(from_block), Some(to_block)),
+    };
+    let (lower, upper) = match (lower, upper) {
+        (Some(lower), Some(upper)) if lower <= upper => (lower, upper),
+        _ => {
+            return TransactionsPage {
+                transactions: Vec::new(),
+                next_cursor: None,
+            }
+        }
+    };
+
+    let mut transactions = Vec::new();
+    let mut next_cursor = None;
+
+    TRANSACTIONS.with(|transactions_ref| {
+        let transactions_borrow = transactions_ref.borrow();
+        let mut ascending = transactions_borrow.range(lower..=upper);
+        let mut next = match direction {
+            SortDirection::Ascending => ascending.next(),
+            SortDirection::Descending => match upper.checked_add(1) {
+                Some(bound) => transactions_borrow.iter_upper_bound(&bound).next(),
+                None => transactions_borrow.last_key_value(),
+            },
+        };
+
+        let mut last_scanned = None;
+        let mut scanned = 0;
+        while let Some((key, transaction)) = next {
+            if key < lower || key > upper {
+                break;
+            }
+            if transactions.len() == limit || scanned == MAX_SCAN {
+                // There is at least one more entry in range past the page
+                next_cursor = last_scanned;
+                break;
+            }
+            last_scanned = Some(key);
+            scanned += 1;
+
+            if matches_filter(&transaction, &filter) {
+                transactions.push(transaction);
+            }
+
+            next = match direction {
+                SortDirection::Ascending => ascending.next(),
+                SortDirection::Descending => transactions_borrow.iter_upper_bound(&key).next(),
+            };
+        }
+    });
+
+    TransactionsPage {
+        transactions,
+        next_cursor,
+    }
+}
+
+fn resolve_account(account: AccountQuery) -> Result<[u8; 32], Error> {
+    match account {
+        AccountQuery::Address(text) => Ok(parse_address(&text)?.account_id),
+        AccountQuery::Icrc1(icrc1_account) => Ok(icrc1_to_account_identifier(&icrc1_account)?),
+        AccountQuery::Nonce(index) => {
+            let stored = SUBACCOUNTS
+                .with(|subaccounts_ref| subaccounts_ref.borrow().get(&index))
+                .ok_or(Error {
+                    message: "Account not found".to_string(),
+                })?;
+            let subaccountid = to_subaccount_id(derive_subaccount(index, &stored)?);
+            from_hex(&subaccountid.to_hex())
+        }
+    }
+}
+
+const MAX_INVOICE_METADATA: usize = 1024;
+
+// Stands in for the owner under the principal-embedded scheme, so that each invoice still
+// gets its own address. Opaque principals end in 0x01.
+fn invoice_principal(id: u64) -> Principal {
+    let mut bytes = b"invoice".to_vec();
+    bytes.extend(id.to_be_bytes());
+    bytes.push(1);
+    Principal::from_slice(&bytes)
 }
 
 #[update]
-fn add_subaccount() -> String {
-    let nonce = get_nonce();
-    let subaccount = to_subaccount(nonce); // needed for storing the subaccount
-    let subaccountid: AccountIdentifier = to_subaccount_id(subaccount.clone()); // needed to get the hashkey & to return to user
-    let account_id_hash = subaccountid.to_u64_hash();
+fn create_invoice(
+    amount_e8s: u64,
+    expires_in_seconds: Option<u64>,
+    memo: Option<u64>,
+    metadata: Option<String>,
+) -> Result<Invoice, Error> {
+    if amount_e8s == 0 {
+        return Err(Error {
+            message: "Invoice amount must be positive".to_string(),
+        });
+    }
+    if metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.len() > MAX_INVOICE_METADATA)
+    {
+        return Err(Error {
+            message: format!(
+                "Invoice metadata is limited to {} bytes",
+                MAX_INVOICE_METADATA
+            ),
+        });
+    }
 
-    LIST_OF_SUBACCOUNTS.with(|list_ref| {
-        list_ref.borrow_mut().insert(account_id_hash, subaccount);
+    let id = INVOICES.with(|invoices_ref| {
+        invoices_ref
+            .borrow()
+            .last_key_value()
+            .map_or(1, |(id, _)| id + 1)
     });
 
-    LAST_SUBACCOUNT_NONCE.with(|nonce_ref| {
-        let _ = nonce_ref.borrow_mut().set(nonce + 1);
+    // Memo 0 is what wallets send by default, so it cannot identify an invoice
+    let deposit_address = match memo {
+        Some(0) => {
+            return Err(Error {
+                message: "Invoice memo must be non-zero".to_string(),
+            });
+        }
+        Some(memo) => {
+            if INVOICE_MEMOS.with(|memos_ref| memos_ref.borrow().contains_key(&memo)) {
+                return Err(Error {
+                    message: "Memo is already used by another invoice".to_string(),
+                });
+            }
+            INVOICE_MEMOS.with(|memos_ref| memos_ref.borrow_mut().insert(memo, id));
+            None
+        }
+        None => {
+            let address = new_subaccount(StoredSubaccount {
+                label: Some(format!("invoice {}", id)),
+                status: SubaccountStatus::Active,
+                external_id: Some(format!("invoice:{}", id)),
+                owner: match get_derivation_scheme() {
+                    DerivationScheme::PrincipalEmbedded => Some(invoice_principal(id)),
+                    _ => None,
+                },
+            })?;
+            let account = from_hex(&address)?;
+            INVOICE_ADDRESSES.with(|addresses_ref| addresses_ref.borrow_mut().insert(account, id));
+            Some(address)
+        }
+    };
+
+    let created_at = Timestamp::from_nanos(now_nanos());
+    let invoice = Invoice {
+        id,
+        amount_e8s,
+        received_e8s: 0,
+        deposit_address,
+        memo,
+        metadata,
+        state: InvoiceState::Pending,
+        expires_at: expires_in_seconds.map(|seconds| {
+            Timestamp::from_nanos(
+                created_at
+                    .timestamp_nanos
+                    .saturating_add(seconds.saturating_mul(1_000_000_000)),
+            )
+        }),
+        created_at,
+        payments: Vec::new(),
+        payment_count: 0,
+    };
+    INVOICES.with(|invoices_ref| invoices_ref.borrow_mut().insert(id, invoice.clone()));
+
+    Ok(invoice)
+}
+
+fn transaction_memo(transaction: &StoredTransactions) -> Option<u64> {
+    match &transaction.icrc1_memo {
+        Some(memo) => Some(u64::from_be_bytes(memo.as_slice().try_into().ok()?)),
+        None => Some(transaction.memo),
+    }
+}
+
+// Counts a deposit towards the invoice for its address, or else for its memo
+fn match_invoice(transaction: &StoredTransactions) {
+    let (account, amount) = match deposit_amount(transaction) {
+        Some(deposit) => deposit,
+        None => return,
+    };
+    let id = INVOICE_ADDRESSES
+        .with(|addresses_ref| addresses_ref.borrow().get(&account))
+        .or_else(|| {
+            transaction_memo(transaction)
+                .filter(|memo| *memo != 0)
+                .and_then(|memo| INVOICE_MEMOS.with(|memos_ref| memos_ref.borrow().get(&memo)))
+        });
+    let id = match id {
+        Some(id) => id,
+        None => return,
+    };
+
+    INVOICES.with(|invoices_ref| {
+        let mut invoices = invoices_ref.borrow_mut();
+        if let Some(mut invoice) = invoices.get(&id) {
+            invoice.apply_payment(transaction.index, amount, transaction.timestamp());
+            invoices.insert(id, invoice);
+        }
     });
+}
 
-    subaccountid.to_hex()
+fn with_current_state(mut invoice: Invoice, now: &Timestamp) -> Invoice {
+    invoice.state = invoice.state_at(now);
+    invoice
 }
 
-#[query]
-fn get_subaccountid(nonce: u32) -> Result<String, Error> {
-    LIST_OF_SUBACCOUNTS.with(|subaccounts| {
-        let subaccounts_borrow = subaccounts.borrow();
+// The memo a deposit carries; a zero legacy memo is what wallets send when none was given
+fn memo_bytes(transaction: &StoredTransactions) -> Option<Vec<u8>> {
+    match &transaction.icrc1_memo {
+        Some(memo) => Some(memo.clone()),
+        None if transaction.memo != 0 => Some(transaction.memo.to_be_bytes().to_vec()),
+        None => None,
+    }
+}
 
-        if nonce as usize >= subaccounts_borrow.len() {
-            return Err(Error {
-                message: "Index out of bounds".to_string(),
+fn memo_reference(memo: &[u8]) -> Option<String> {
+    let key = Blob::try_from(memo).ok()?;
+    MEMO_REFERENCES.with(|references_ref| references_ref.borrow().get(&key))
+}
+
+// Deposits to a memo-mode account are attributed by their memo, or queued for review
+fn attribute_deposit(transaction: &StoredTransactions) {
+    let account = match deposit_amount(transaction) {
+        Some((account, _)) => account,
+        None => return,
+    };
+    if !MEMO_ACCOUNTS.with(|accounts_ref| accounts_ref.borrow().contains_key(&account)) {
+        return;
+    }
+
+    match memo_bytes(transaction).and_then(|memo| memo_reference(&memo)) {
+        Some(reference) => {
+            DEPOSIT_REFERENCES.with(|references_ref| {
+                references_ref
+                    .borrow_mut()
+                    .insert(transaction.index, reference)
             });
         }
-       
-        let subaccount = to_subaccount(nonce);
-        let subaccountid: AccountIdentifier = to_subaccount_id(subaccount.clone()); 
-        let account_id_hash = subaccountid.to_u64_hash();
+        None => {
+            REVIEW_QUEUE.with(|queue_ref| queue_ref.borrow_mut().insert(transaction.index, ()));
+        }
+    }
+}
 
-        ic_cdk::println!("account_id_hash to search: {}", account_id_hash);
+#[update]
+fn set_memo_attribution(account: AccountQuery, enabled: bool) -> Result<(), Error> {
+    let account = resolve_account(account)?;
+    let hash = account.to_u64_hash();
+    if !LIST_OF_SUBACCOUNTS.with(|list_ref| list_ref.borrow().contains_key(&hash)) {
+        return Err(Error {
+            message: "Account is not one of our subaccounts".to_string(),
+        });
+    }
 
-        // find matching hashkey
-        match subaccounts_borrow.get(&account_id_hash) {
-            Some(_) => Ok(subaccountid.to_hex()),
-            None => Err(Error {
-                message: "Account not found".to_string(),
-            }),
+    MEMO_ACCOUNTS.with(|accounts_ref| {
+        let mut accounts = accounts_ref.borrow_mut();
+        if enabled {
+            accounts.insert(account, ());
+        } else {
+            accounts.remove(&account);
         }
-    })
+    });
+    Ok(())
+}
+
+// Maps a memo to an external reference, or removes the mapping when the reference is None.
+// Only deposits indexed afterwards are affected.
+#[update]
+fn set_memo_reference(memo: MemoValue, reference: Option<String>) -> Result<(), Error> {
+    let key = Blob::<32>::try_from(memo.bytes().as_slice()).map_err(|_| Error {
+        message: "Memo is longer than 32 bytes".to_string(),
+    })?;
+
+    MEMO_REFERENCES.with(|references_ref| {
+        let mut references = references_ref.borrow_mut();
+        match reference {
+            Some(reference) => references.insert(key, reference),
+            None => references.remove(&key),
+        }
+    });
+    Ok(())
 }
 
 #[query]
-fn get_subaccount_count() -> u32 {
-    LIST_OF_SUBACCOUNTS.with(|subaccounts| subaccounts.borrow().len() as u32)
+fn get_deposit_reference(block_index: u64) -> Option<String> {
+    DEPOSIT_REFERENCES.with(|references_ref| references_ref.borrow().get(&block_index))
 }
 
 #[query]
-fn get_transactions_count() -> u32 {
-    TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow().len() as u32)
+fn list_review_queue(start_after: Option<u64>, limit: Option<u64>) -> TransactionsPage {
+    let limit = limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+    let start = match start_after {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+
+    let mut indices: Vec<u64> = REVIEW_QUEUE.with(|queue_ref| {
+        queue_ref
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit + 1)
+            .map(|(index, _)| index)
+            .collect()
+    });
+    let next_cursor = if indices.len() > limit {
+        indices.truncate(limit);
+        indices.last().copied()
+    } else {
+        None
+    };
+
+    TransactionsPage {
+        transactions: TRANSACTIONS.with(|transactions_ref| {
+            let transactions = transactions_ref.borrow();
+            indices
+                .iter()
+                .filter_map(|index| transactions.get(index))
+                .collect()
+        }),
+        next_cursor,
+    }
+}
+
+// Takes a deposit off the review queue, attributing it to the reference if one is given
+#[update]
+fn resolve_review(block_index: u64, reference: Option<String>) -> Result<(), Error> {
+    let queued = REVIEW_QUEUE.with(|queue_ref| queue_ref.borrow_mut().remove(&block_index));
+    if queued.is_none() {
+        return Err(Error {
+            message: "Deposit is not awaiting review".to_string(),
+        });
+    }
+
+    if let Some(reference) = reference {
+        DEPOSIT_REFERENCES
+            .with(|references_ref| references_ref.borrow_mut().insert(block_index, reference));
+    }
+    Ok(())
 }
 
 #[query]
-fn get_oldest_block() -> Option<u64> {
-    TRANSACTIONS.with(|transactions_ref| {
-        let transactions_borrow = transactions_ref.borrow();
-        transactions_borrow.iter().next().map(|(key, _value)| key)
-    })
+fn get_invoice(id: u64) -> Result<Invoice, Error> {
+    let now = Timestamp::from_nanos(now_nanos());
+    INVOICES
+        .with(|invoices_ref| invoices_ref.borrow().get(&id))
+        .map(|invoice| with_current_state(invoice, &now))
+        .ok_or(Error {
+            message: "Invoice not found".to_string(),
+        })
 }
 
 #[query]
-fn list_transactions(up_to_count: Option<u64>) -> Vec<StoredTransactions> {
-    // process argument
-    let up_to_count = match up_to_count {
-        Some(count) => count,
-        None => 100, // Default is 100
+fn list_invoices(req: ListInvoicesRequest) -> InvoicesPage {
+    let limit = req.limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+    let now = Timestamp::from_nanos(now_nanos());
+    let start = match req.start_after {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
     };
 
-    // get earliest block
-    // if there are no transactions, return empty `result`
-    let mut result = Vec::new();
+    let mut invoices = Vec::new();
+    let mut next_cursor = None;
+    INVOICES.with(|invoices_ref| {
+        let mut last_scanned = None;
+        for (scanned, (id, invoice)) in invoices_ref
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .enumerate()
+        {
+            if invoices.len() == limit || scanned == MAX_SCAN {
+                next_cursor = last_scanned;
+                break;
+            }
+            last_scanned = Some(id);
 
-    TRANSACTIONS.with(|transactions_ref| {
-        let transactions_borrow = transactions_ref.borrow();
+            let invoice = with_current_state(invoice, &now);
+            if req.state.is_none() || req.state.as_ref() == Some(&invoice.state) {
+                invoices.push(invoice);
+            }
+        }
+    });
+
+    InvoicesPage {
+        invoices,
+        next_cursor,
+    }
+}
 
-        ic_cdk::println!("transactions_len: {}", transactions_borrow.len());
+#[query]
+fn get_account_summary(account: AccountQuery) -> Result<AccountSummary, Error> {
+    let account = resolve_account(account)?;
+    Ok(ACCOUNT_SUMMARIES
+        .with(|summaries_ref| summaries_ref.borrow().get(&account))
+        .unwrap_or_default())
+}
 
-        // If transactions_borrow.len() is less than up_to_count, return all transactions
-        let skip = if transactions_borrow.len() < up_to_count {
-            0
-        } else {
-            transactions_borrow.len() - up_to_count
-        };
+#[query]
+fn get_totals() -> AccountSummary {
+    TOTALS.with(|totals_ref| totals_ref.borrow().get().clone())
+}
+
+// Counts for the HTTP metrics route
+fn collect_metrics() -> Metrics {
+    Metrics {
+        transaction_count: TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow().len()),
+        subaccount_count: SUBACCOUNTS.with(|subaccounts_ref| subaccounts_ref.borrow().len()),
+        watched_account_count: WATCHED_ACCOUNTS.with(|watched_ref| watched_ref.borrow().len()),
+        latest_event_seq: latest_event_seq(),
+        review_queue_length: REVIEW_QUEUE.with(|queue_ref| queue_ref.borrow().len()),
+        invoice_count: INVOICES.with(|invoices_ref| invoices_ref.borrow().len()),
+        subscriber_count: SUBSCRIBERS.with(|subscribers_ref| subscribers_ref.borrow().len()),
+        webhook_count: WEBHOOKS.with(|webhooks_ref| webhooks_ref.borrow().len()),
+        totals: get_totals(),
+    }
+}
+
+#[query]
+fn http_request(req: http::HttpRequest) -> http::HttpResponse {
+    http::handle(&req)
+}
+
+#[query]
+fn list_transactions_for_account(
+    req: AccountTransactionsRequest,
+) -> Result<TransactionsPage, Error> {
+    let account = resolve_account(req.account)?;
+    let limit = req.limit.unwrap_or(100).min(MAX_PAGE_SIZE) as usize; // Default is 100
+
+    // Fetch one extra entry to find out whether another page follows
+    let mut blocks: Vec<u64> = ACCOUNT_TRANSACTIONS.with(|index_ref| {
+        let index = index_ref.borrow();
+        match req.direction.unwrap_or(SortDirection::Ascending) {
+            SortDirection::Ascending => {
+                let start = match req.start_after {
+                    Some(cursor) => Bound::Excluded((account, cursor)),
+                    None => Bound::Included((account, 0)),
+                };
+                index
+                    .range((start, Bound::Included((account, u64::MAX))))
+                    .take(limit + 1)
+                    .map(|((_, block), _)| block)
+                    .collect()
+            }
+            SortDirection::Descending => {
+                let start_before = (account, req.start_after.unwrap_or(u64::MAX));
+                range_descending(&index, Some(start_before), Some(&(account, 0)), limit + 1)
+                    .into_iter()
+                    .map(|((_, block), _)| block)
+                    .collect()
+            }
+        }
+    });
+
+    let next_cursor = if blocks.len() > limit {
+        blocks.truncate(limit);
+        blocks.last().copied()
+    } else {
+        None
+    };
 
-        ic_cdk::println!("skip: {}", skip);
-        transactions_borrow
+    let transactions = TRANSACTIONS.with(|transactions_ref| {
+        let transactions_borrow = transactions_ref.borrow();
+        blocks
             .iter()
-            .skip(skip as usize)
-            .take(up_to_count as usize)
-            .for_each(|(_key, value)| {
-                result.push(value.clone());
-            });
-        result
+            .filter_map(|block| transactions_borrow.get(block))
+            .collect()
+    });
+
+    Ok(TransactionsPage {
+        transactions,
+        next_cursor,
     })
 }
 
+#[cfg(not(test))]
+fn instructions_used() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+#[cfg(test)]
+fn instructions_used() -> u64 {
+    0
+}
+
+#[cfg(not(test))]
+fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+#[cfg(test)]
+thread_local! {
+    static CALLER: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+}
+
+#[cfg(test)]
+fn caller() -> Principal {
+    CALLER.with(|caller_ref| *caller_ref.borrow())
+}
+
+#[cfg(not(test))]
+fn is_controller(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+#[cfg(not(test))]
+fn own_principal() -> Principal {
+    ic_cdk::id()
+}
+
+#[cfg(test)]
+fn own_principal() -> Principal {
+    Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1])
+}
+
+// Tests run as the anonymous principal, which stands in for the controller
+#[cfg(test)]
+fn is_controller(principal: &Principal) -> bool {
+    *principal == Principal::anonymous()
+}
+
+fn require_controller() -> Result<(), Error> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error {
+            message: "Caller is not a controller".to_string(),
+        })
+    }
+}
+
+// Leaves ample headroom below the per-message instruction limit for the reply
+const CLEAR_INSTRUCTION_BUDGET: u64 = 4_000_000_000;
+
+// Removes entries up to `up_to_index`, or with a ledger timestamp up to `up_to_timestamp`,
+// walking forward from `start_after`. Unsettled deposits and deposits awaiting review are
+// kept unless `force` is set, and forced deletions of them are recorded in the audit log.
 #[update]
 fn clear_transactions(
     up_to_index: Option<u64>,
     up_to_timestamp: Option<Timestamp>,
-) -> Result<Vec<StoredTransactions>, Error> {
+    start_after: Option<u64>,
+    force: Option<bool>,
+) -> Result<ClearTransactionsResponse, Error> {
     // Get Data
-    let up_to_index = match up_to_index {
-        Some(index) => index,
-        None => 0,
+    let up_to_index = up_to_index.unwrap_or(0);
+    let up_to_timestamp = up_to_timestamp.unwrap_or(Timestamp::from_nanos(0));
+    let force = force.unwrap_or(false);
+
+    let mut response = ClearTransactionsResponse {
+        removed: 0,
+        kept_unsettled: 0,
+        remaining: 0,
+        next_cursor: None,
     };
-    let up_to_timestamp = match up_to_timestamp {
-        Some(timestamp) => timestamp,
-        None => Timestamp::from_nanos(0),
+    let mut forced_indices = Vec::new();
+    let mut forced_amount_e8s: u64 = 0;
+
+    // Without a timestamp nothing past up_to_index can match
+    let upper = match (up_to_index, up_to_timestamp.timestamp_nanos) {
+        (0, 0) => None,
+        (index, 0) => Some(Bound::Included(index)),
+        _ => Some(Bound::Unbounded),
     };
 
-    TRANSACTIONS.with(|transactions_ref| {
-        // Collect keys that are less than the cutoff
-        let mut transactions_borrow = transactions_ref.borrow_mut();
-        let keys_to_remove: Vec<u64> = transactions_borrow
-            .iter()
-            .filter(|transaction| {
-                // If up_to_index is set then remove transactions with a index less than up_to_index
-                // If up_to_timestamp is set then remove transactions with a timestamp less than up_to_timestamp
-                (up_to_index != 0 && transaction.1.index <= up_to_index)
-                    || (up_to_timestamp.timestamp_nanos != 0
-                        && transaction.1.created_at_time.timestamp_nanos
-                            <= up_to_timestamp.timestamp_nanos)
-            })
-            .map(|(k, _)| k)
-            .collect();
+    if let Some(upper) = upper {
+        let budget_start = instructions_used();
+        let mut next = match start_after {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let mut scanned = 0;
+
+        loop {
+            if scanned == MAX_SCAN
+                || instructions_used().saturating_sub(budget_start) > CLEAR_INSTRUCTION_BUDGET
+            {
+                response.next_cursor = match next {
+                    Bound::Excluded(cursor) => Some(cursor),
+                    _ => None,
+                };
+                break;
+            }
+
+            let entry = TRANSACTIONS
+                .with(|transactions_ref| transactions_ref.borrow().range((next, upper)).next());
+            let (key, transaction) = match entry {
+                Some(entry) => entry,
+                None => break,
+            };
+            next = Bound::Excluded(key);
+            scanned += 1;
+
+            // If up_to_index is set then remove transactions with a index less than up_to_index
+            // If up_to_timestamp is set then remove transactions with a timestamp less than up_to_timestamp
+            let matches = (up_to_index != 0 && transaction.index <= up_to_index)
+                || (up_to_timestamp.timestamp_nanos != 0
+                    && transaction.timestamp().timestamp_nanos <= up_to_timestamp.timestamp_nanos);
+            if !matches {
+                continue;
+            }
+
+            if is_unsettled_deposit(&transaction) || awaiting_review(key) {
+                if !force {
+                    response.kept_unsettled += 1;
+                    continue;
+                }
+                forced_indices.push(key);
+                if let Some((_account, amount)) = deposit_amount(&transaction) {
+                    forced_amount_e8s = forced_amount_e8s.saturating_add(amount);
+                }
+            }
 
-        // Remove elements with those keys
-        for key in keys_to_remove {
-            transactions_borrow.remove(&key);
+            remove_transaction(key);
+            response.removed += 1;
         }
+    }
 
-        let mut result = Vec::new();
-        transactions_borrow.iter().for_each(|(_key, value)| {
-            result.push(value.clone());
+    if !forced_indices.is_empty() {
+        append_audit(AuditEvent::ForcedClear {
+            caller: caller(),
+            indices: forced_indices,
+            amount_e8s: forced_amount_e8s,
         });
-        Ok(result)
-    })
+    }
+
+    response.remaining = TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow().len());
+    Ok(response)
 }
 
 #[update]
-fn refund(transaction_index: u64) -> Result<String, Error> {
+fn refund(transaction_index: u64, destination: Option<String>) -> Result<String, Error> {
     let ledger_principal_opt = PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().clone());
 
     let ledger_principal = match ledger_principal_opt.get_principal() {
@@ -576,11 +3248,27 @@ fn refund(transaction_index: u64) -> Result<String, Error> {
         }
     };
 
-    let subaccount = match transaction.operation {
+    if !transaction.is_inbound() {
+        return Err(Error {
+            message: "Only inbound deposits can be refunded".to_string(),
+        });
+    }
+    if transaction.is_settled() {
+        return Err(Error {
+            message: "Transaction is already swept or refunded".to_string(),
+        });
+    }
+
+    let subaccount = match &transaction.operation {
         Some(Operation::Transfer(data)) => {
             let to = data.to.clone();
             match &data.spender {
-                Some(spender) => (includes_hash(&to), to, spender.clone(), data.amount.e8s),
+                Some(spender) => (
+                    includes_hash(&to) && !is_watch_only(&to),
+                    to,
+                    spender.clone(),
+                    data.amount.e8s,
+                ),
                 None => (false, to, vec![], data.amount.e8s),
             }
         }
@@ -593,11 +3281,40 @@ fn refund(transaction_index: u64) -> Result<String, Error> {
         });
     }
 
-    let to_record = ToRecord::new(Principal::from_slice(&subaccount.2), None);
-    let req = Icrc1TransferRequest::new(to_record, None, None, Some(subaccount.1), None, 1000);
-
+    // Without an explicit destination the refund goes back to the spender
+    let to_record = match destination {
+        Some(destination) => {
+            let account = parse_icrc1_account(&destination)?;
+            ToRecord::new(account.owner, account.subaccount)
+        }
+        None => ToRecord::new(Principal::from_slice(&subaccount.2), None),
+    };
+    // The memo lets a failed transfer mark the entry as FailedToSweep, as for sweeps
+    let req = Icrc1TransferRequest::new(
+        to_record,
+        None,
+        Some(transaction_index.to_be_bytes().to_vec()),
+        Some(subaccount.1),
+        None,
+        1000,
+    );
+
+    TRANSFERS_IN_FLIGHT.with(|in_flight_ref| {
+        in_flight_ref
+            .borrow_mut()
+            .insert(transaction_index, SweepStatus::Refunded)
+    });
     IcCdkSpawnManager::run(call_icrc1_transfer(ledger_principal, req));
 
+    let mut transaction = transaction;
+    change_sweep_status(&mut transaction, SweepStatus::Refunded);
+    certify_transaction(&transaction);
+    TRANSACTIONS.with(|transactions_ref| {
+        transactions_ref
+            .borrow_mut()
+            .insert(transaction_index, transaction);
+    });
+
     Ok("Refund is being requested".to_string())
 }
 
@@ -631,7 +3348,11 @@ fn sweep_user_vault() -> Result<String, Error> {
             transactions_ref
                 .borrow()
                 .iter()
-                .filter(|transaction| transaction.1.sweep_status == SweepStatus::NotSwept)
+                .filter(|transaction| {
+                    transaction.1.sweep_status == SweepStatus::NotSwept
+                        && transaction.1.flag.is_none()
+                        && transaction.1.is_inbound()
+                })
                 .map(|(key, transaction)| (key.clone(), transaction.clone()))
                 .collect()
         };
@@ -642,7 +3363,11 @@ fn sweep_user_vault() -> Result<String, Error> {
             let subaccount = match transaction.clone().operation {
                 Some(Operation::Transfer(data)) => {
                     let to = data.to.clone();
-                    (includes_hash(&to), to, data.amount.e8s)
+                    (
+                        includes_hash(&to) && !is_watch_only(&to),
+                        to,
+                        data.amount.e8s,
+                    )
                 }
                 _ => (false, vec![], 0),
             };
@@ -658,9 +3383,15 @@ fn sweep_user_vault() -> Result<String, Error> {
                     subaccount.2,
                 );
 
+                TRANSFERS_IN_FLIGHT.with(|in_flight_ref| {
+                    in_flight_ref
+                        .borrow_mut()
+                        .insert(transaction.index, SweepStatus::Swept)
+                });
                 IcCdkSpawnManager::run(call_icrc1_transfer(ledger_principal, req));
 
-                transaction.sweep_status = SweepStatus::Swept;
+                change_sweep_status(transaction, SweepStatus::Swept);
+                certify_transaction(transaction);
 
                 transaction_borrow_mut.remove(&key);
                 transaction_borrow_mut.insert(key.clone(), transaction.clone());
@@ -671,10 +3402,66 @@ fn sweep_user_vault() -> Result<String, Error> {
     Ok("Subaccounts are swept to vault".to_string())
 }
 
+#[cfg(not(test))]
+fn cycles_balance() -> u128 {
+    ic_cdk::api::canister_balance128()
+}
+
+#[cfg(test)]
+fn cycles_balance() -> u128 {
+    0
+}
+
+#[cfg(not(test))]
+fn stable_memory_bytes() -> u64 {
+    ic_cdk::api::stable::stable64_size() * 65536
+}
+
+#[cfg(test)]
+fn stable_memory_bytes() -> u64 {
+    0
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * 65536
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_bytes() -> u64 {
+    0
+}
+
 #[query]
-fn canister_status() -> Result<String, Error> {
-    // Stub implementation - Return a placeholder JSON response
-    Ok(format!("{{\"message\": \"Canister is operational\"}}"))
+fn canister_status() -> CanisterStatus {
+    let next_block = NEXT_BLOCK.with(|next_block_ref| *next_block_ref.borrow().get());
+    let sync = SYNC_STATE.with(|state_ref| state_ref.borrow().get().clone());
+    let (pending_sweeps, pending_refunds) = TRANSFERS_IN_FLIGHT.with(|in_flight_ref| {
+        let in_flight = in_flight_ref.borrow();
+        let count = |status: SweepStatus| {
+            in_flight
+                .values()
+                .filter(|pending| **pending == status)
+                .count() as u64
+        };
+        (count(SweepStatus::Swept), count(SweepStatus::Refunded))
+    });
+
+    CanisterStatus {
+        cycles: cycles_balance(),
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: stable_memory_bytes(),
+        stable_memory: stable_memory_usage(),
+        next_block,
+        sync_lag: sync
+            .chain_length
+            .map(|chain_length| chain_length.saturating_sub(next_block)),
+        sync,
+        sync_timer_active: TIMERS.with(|timers_ref| *timers_ref.borrow() != TimerId::default()),
+        interval_seconds: INTERVAL_IN_SECONDS.with(|interval_ref| *interval_ref.borrow().get()),
+        pending_sweeps,
+        pending_refunds,
+    }
 }
 
 // Enable Candid export
@@ -1,16 +1,58 @@
+use candid::Principal;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+use ic_stable_structures::storable::Blob;
 use ic_stable_structures::DefaultMemoryImpl;
+use ic_stable_structures::Memory as _;
 use ic_stable_structures::{StableBTreeMap, StableCell};
 use std::cell::RefCell;
 
-use crate::types::{Memory, StoredPrincipal, StoredTransactions};
+use crate::types::{
+    AccountSummary, AuditRecord, BackfillState, ChainTip, DerivationScheme, Event, Invoice, Memory,
+    MigrationState, RetentionPolicy, StableMemoryUsage, StoredPrincipal, StoredSubaccount,
+    StoredTransactions, StoredWatchedAccount, Subscriber, SyncState, Webhook,
+};
 
-const PRINCIPAL_MEMORY: MemoryId = MemoryId::new(0);
-const LAST_SUBACCOUNT_NONCE_MEMORY: MemoryId = MemoryId::new(1);
-const NEXT_BLOCK_MEMORY: MemoryId = MemoryId::new(2);
-const INTERVAL_IN_SECONDS_MEMORY: MemoryId = MemoryId::new(3);
-const TRANSACTIONS_MEMORY: MemoryId = MemoryId::new(4);
-const CUSTODIAN_PRINCIPAL_MEMORY: MemoryId = MemoryId::new(5);
+// Declares each memory's id and lists them all, by name, for reporting
+macro_rules! memories {
+    ($($name:ident: $memory:ident = $id:literal,)*) => {
+        $(const $memory: MemoryId = MemoryId::new($id);)*
+        const MEMORIES: &[(&str, u8)] = &[$((stringify!($name), $id),)*];
+    };
+}
+
+memories! {
+    PRINCIPAL: PRINCIPAL_MEMORY = 0,
+    LAST_SUBACCOUNT_NONCE: LAST_SUBACCOUNT_NONCE_MEMORY = 1,
+    NEXT_BLOCK: NEXT_BLOCK_MEMORY = 2,
+    INTERVAL_IN_SECONDS: INTERVAL_IN_SECONDS_MEMORY = 3,
+    TRANSACTIONS: TRANSACTIONS_MEMORY = 4,
+    CUSTODIAN_PRINCIPAL: CUSTODIAN_PRINCIPAL_MEMORY = 5,
+    SUBACCOUNTS: SUBACCOUNTS_MEMORY = 6,
+    WATCHED_ACCOUNTS: WATCHED_ACCOUNTS_MEMORY = 7,
+    DERIVATION_SCHEME: DERIVATION_SCHEME_MEMORY = 8,
+    ACCOUNT_TRANSACTIONS: ACCOUNT_TRANSACTIONS_MEMORY = 9,
+    MIGRATION_STATE: MIGRATION_STATE_MEMORY = 10,
+    RETENTION_POLICY: RETENTION_POLICY_MEMORY = 11,
+    AUDIT_LOG: AUDIT_LOG_MEMORY = 12,
+    ACCOUNT_SUMMARIES: ACCOUNT_SUMMARIES_MEMORY = 13,
+    TOTALS: TOTALS_MEMORY = 14,
+    INVOICES: INVOICES_MEMORY = 15,
+    INVOICE_ADDRESSES: INVOICE_ADDRESSES_MEMORY = 16,
+    INVOICE_MEMOS: INVOICE_MEMOS_MEMORY = 17,
+    MEMO_ACCOUNTS: MEMO_ACCOUNTS_MEMORY = 18,
+    MEMO_REFERENCES: MEMO_REFERENCES_MEMORY = 19,
+    DEPOSIT_REFERENCES: DEPOSIT_REFERENCES_MEMORY = 20,
+    REVIEW_QUEUE: REVIEW_QUEUE_MEMORY = 21,
+    EVENTS: EVENTS_MEMORY = 22,
+    SUBSCRIBERS: SUBSCRIBERS_MEMORY = 23,
+    WEBHOOKS: WEBHOOKS_MEMORY = 24,
+    WEBHOOK_SECRETS: WEBHOOK_SECRETS_MEMORY = 25,
+    CHAIN_TIP: CHAIN_TIP_MEMORY = 26,
+    SYNC_STATE: SYNC_STATE_MEMORY = 27,
+    BACKFILL_STATE: BACKFILL_STATE_MEMORY = 28,
+}
+
+const WASM_PAGE_SIZE: u64 = 65536;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
@@ -52,4 +94,155 @@ thread_local! {
             StoredPrincipal::default() // TODO: add to init function
         ).expect("Initializing CUSTODIAN_PRINCIPAL StableCell failed")
     );
+    // Keyed by subaccount index (the nonce for the default scheme)
+    pub static SUBACCOUNTS: RefCell<StableBTreeMap<u64, StoredSubaccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SUBACCOUNTS_MEMORY))
+        )
+    );
+    // Keyed by account identifier
+    pub static WATCHED_ACCOUNTS: RefCell<StableBTreeMap<[u8; 32], StoredWatchedAccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(WATCHED_ACCOUNTS_MEMORY))
+        )
+    );
+    pub static DERIVATION_SCHEME: RefCell<StableCell<DerivationScheme, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DERIVATION_SCHEME_MEMORY)),
+            DerivationScheme::default()
+        ).expect("Initializing DERIVATION_SCHEME StableCell failed")
+    );
+    // (account identifier, block index) pairs for every indexed transaction touching the account
+    pub static ACCOUNT_TRANSACTIONS: RefCell<StableBTreeMap<([u8; 32], u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ACCOUNT_TRANSACTIONS_MEMORY))
+        )
+    );
+    // Starts at version 0 on canisters upgraded from before the envelope; init sets the current one
+    pub static MIGRATION_STATE: RefCell<StableCell<MigrationState, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MIGRATION_STATE_MEMORY)),
+            MigrationState::default()
+        ).expect("Initializing MIGRATION_STATE StableCell failed")
+    );
+    pub static RETENTION_POLICY: RefCell<StableCell<RetentionPolicy, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RETENTION_POLICY_MEMORY)),
+            RetentionPolicy::default()
+        ).expect("Initializing RETENTION_POLICY StableCell failed")
+    );
+    // Keyed by record id, assigned in increasing order
+    pub static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(AUDIT_LOG_MEMORY))
+        )
+    );
+    // Deposit aggregates by receiving account identifier
+    pub static ACCOUNT_SUMMARIES: RefCell<StableBTreeMap<[u8; 32], AccountSummary, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ACCOUNT_SUMMARIES_MEMORY))
+        )
+    );
+    pub static TOTALS: RefCell<StableCell<AccountSummary, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOTALS_MEMORY)),
+            AccountSummary::default()
+        ).expect("Initializing TOTALS StableCell failed")
+    );
+    pub static INVOICES: RefCell<StableBTreeMap<u64, Invoice, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(INVOICES_MEMORY))
+        )
+    );
+    // Dedicated deposit account identifier to invoice id
+    pub static INVOICE_ADDRESSES: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(INVOICE_ADDRESSES_MEMORY))
+        )
+    );
+    // Memo to invoice id; memos are never reused
+    pub static INVOICE_MEMOS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(INVOICE_MEMOS_MEMORY))
+        )
+    );
+    // Account identifiers whose deposits are attributed by memo
+    pub static MEMO_ACCOUNTS: RefCell<StableBTreeMap<[u8; 32], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MEMO_ACCOUNTS_MEMORY))
+        )
+    );
+    // Memo bytes to external reference; ICRC-1 memos are at most 32 bytes
+    pub static MEMO_REFERENCES: RefCell<StableBTreeMap<Blob<32>, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MEMO_REFERENCES_MEMORY))
+        )
+    );
+    // Block index to the external reference a deposit was attributed to
+    pub static DEPOSIT_REFERENCES: RefCell<StableBTreeMap<u64, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DEPOSIT_REFERENCES_MEMORY))
+        )
+    );
+    // Block indices of memo-mode deposits whose memo did not resolve
+    pub static REVIEW_QUEUE: RefCell<StableBTreeMap<u64, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(REVIEW_QUEUE_MEMORY))
+        )
+    );
+    // Append-only change feed keyed by sequence number, starting at 1
+    pub static EVENTS: RefCell<StableBTreeMap<u64, Event, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(EVENTS_MEMORY))
+        )
+    );
+    pub static SUBSCRIBERS: RefCell<StableBTreeMap<Principal, Subscriber, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SUBSCRIBERS_MEMORY))
+        )
+    );
+    pub static WEBHOOKS: RefCell<StableBTreeMap<u64, Webhook, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(WEBHOOKS_MEMORY))
+        )
+    );
+    // HMAC keys by webhook id
+    pub static WEBHOOK_SECRETS: RefCell<StableBTreeMap<u64, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(WEBHOOK_SECRETS_MEMORY))
+        )
+    );
+    pub static CHAIN_TIP: RefCell<StableCell<ChainTip, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CHAIN_TIP_MEMORY)),
+            ChainTip::default()
+        ).expect("Initializing CHAIN_TIP StableCell failed")
+    );
+    pub static SYNC_STATE: RefCell<StableCell<SyncState, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SYNC_STATE_MEMORY)),
+            SyncState::default()
+        ).expect("Initializing SYNC_STATE StableCell failed")
+    );
+    // Starts unfinished on canisters upgraded from before the index and aggregates; init finishes it
+    pub static BACKFILL_STATE: RefCell<StableCell<BackfillState, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BACKFILL_STATE_MEMORY)),
+            BackfillState::default()
+        ).expect("Initializing BACKFILL_STATE StableCell failed")
+    );
+}
+
+pub fn stable_memory_usage() -> Vec<StableMemoryUsage> {
+    MEMORY_MANAGER.with(|m| {
+        let manager = m.borrow();
+        MEMORIES
+            .iter()
+            .map(|&(name, id)| StableMemoryUsage {
+                memory_id: id,
+                name: name.to_string(),
+                size_bytes: manager.get(MemoryId::new(id)).size() * WASM_PAGE_SIZE,
+            })
+            .collect()
+    })
 }
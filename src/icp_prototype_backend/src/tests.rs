@@ -1,5 +1,6 @@
 #[cfg(test)]
 mod tests {
+    use crate::address::*;
     use crate::types::*;
     use crate::*;
     use once_cell::sync::Lazy;
@@ -10,6 +11,10 @@ mod tests {
             TimerId::default()
         }
 
+        fn set_timer_once(_delay: std::time::Duration, _task: fn()) -> TimerId {
+            TimerId::default()
+        }
+
         fn clear_timer(_timer_id: TimerId) {}
     }
 
@@ -252,8 +257,8 @@ mod tests {
         let specific_timestamp = Timestamp::from_nanos(nanos);
         populate_transactions(100, None);
 
-        let cleared = clear_transactions(None, Some(specific_timestamp)).unwrap();
-        assert_eq!(cleared.len(), 0);
+        let cleared = clear_transactions(None, Some(specific_timestamp), None, Some(true)).unwrap();
+        assert_eq!(cleared.remaining, 0);
     }
 
     #[test]
@@ -263,16 +268,16 @@ mod tests {
         let specific_timestamp = Timestamp::from_nanos(nanos);
         populate_transactions(100, Some(nanos));
 
-        let cleared = clear_transactions(None, Some(specific_timestamp)).unwrap();
-        assert_eq!(cleared.len(), 0);
+        let cleared = clear_transactions(None, Some(specific_timestamp), None, Some(true)).unwrap();
+        assert_eq!(cleared.remaining, 0);
     }
 
     #[test]
     fn clear_transactions_with_none_parameters() {
         populate_transactions(100, None);
 
-        let cleared = clear_transactions(None, None).unwrap();
-        assert_eq!(cleared.len(), 100); // Assuming no transactions are removed
+        let cleared = clear_transactions(None, None, None, Some(true)).unwrap();
+        assert_eq!(cleared.remaining, 100); // Assuming no transactions are removed
     }
 
     #[test]
@@ -281,10 +286,9 @@ mod tests {
         populate_transactions(100, None);
 
         // Clear transactions up to a specific index, excluding transactions with a higher index
-        let cleared = clear_transactions(Some(50), None).unwrap();
+        let cleared = clear_transactions(Some(50), None, None, Some(true)).unwrap();
         assert_eq!(
-            cleared.len(),
-            50,
+            cleared.remaining, 50,
             "Expected 50 transactions to remain after clearing up to index 50"
         );
     }
@@ -294,11 +298,16 @@ mod tests {
         populate_transactions(100, Some(50000)); // Populate 100 transactions, all with the same timestamp for simplicity
 
         // Clear transactions with a count less than 80 and a timestamp less than 60000 nanoseconds
-        let cleared = clear_transactions(Some(80), Some(Timestamp::from_nanos(60000))).unwrap();
+        let cleared = clear_transactions(
+            Some(80),
+            Some(Timestamp::from_nanos(60000)),
+            None,
+            Some(true),
+        )
+        .unwrap();
         // This assumes that the criteria are combined with an OR logic, not AND
         assert_eq!(
-            cleared.len(),
-            0,
+            cleared.remaining, 0,
             "Expected 0 transactions to remain after applying multiple clear criteria"
         );
     }
@@ -308,10 +317,12 @@ mod tests {
         populate_transactions(100, Some(100000)); // Populate transactions with a specific timestamp
 
         // Clear transactions with a timestamp exactly equal to one of the transactions' timestamps
-        let cleared = clear_transactions(None, Some(Timestamp::from_nanos(100000))).unwrap();
+        let cleared =
+            clear_transactions(None, Some(Timestamp::from_nanos(100000)), None, Some(true))
+                .unwrap();
         // Depending on implementation, this may remove all transactions if they're considered "up to and including" the given timestamp
         assert!(
-            cleared.is_empty(),
+            cleared.remaining == 0,
             "Expected all transactions to be cleared with a timestamp exactly matching the filter"
         );
     }
@@ -321,14 +332,14 @@ mod tests {
         populate_transactions(10, None);
 
         // Edge case 1: up_to_index is larger than the total transactions
-        let cleared = clear_transactions(Some(50), None).unwrap();
-        assert_eq!(cleared.len(), 0); // Assuming all transactions are cleared
+        let cleared = clear_transactions(Some(50), None, None, Some(true)).unwrap();
+        assert_eq!(cleared.remaining, 0); // Assuming all transactions are cleared
 
         // Edge case 2: up_to_timestamp is before any stored transaction
         let early_timestamp = Timestamp::from_nanos(1); // Example early timestamp
         populate_transactions(10, None); // Repopulate transactions after they were all cleared
-        let cleared = clear_transactions(None, Some(early_timestamp)).unwrap();
-        assert_eq!(cleared.len(), 10); // Assuming no transactions are removed because all are after the timestamp
+        let cleared = clear_transactions(None, Some(early_timestamp), None, Some(true)).unwrap();
+        assert_eq!(cleared.remaining, 10); // Assuming no transactions are removed because all are after the timestamp
     }
 
     #[test]
@@ -343,11 +354,11 @@ mod tests {
             "Expected to list only the last 100 transactions from a large dataset"
         );
 
-        let cleared = clear_transactions(Some(large_number / 2), None).unwrap();
+        let cleared = clear_transactions(Some(large_number / 2), None, None, Some(true)).unwrap();
         // Expecting half of the transactions to be cleared
         assert_eq!(
-            cleared.len(),
-            (large_number / 2) as usize,
+            cleared.remaining,
+            large_number / 2,
             "Expected a maximum of 100 transactions to be returned after clearing a large number"
         );
     }
@@ -375,10 +386,63 @@ mod tests {
             let response = Icrc1TransferResponse::Ok(12345); // Example transaction ID
             Ok((response,))
         }
+
+        // Records the sequence numbers sent; a method named "unreachable" fails to enqueue
+        fn notify_events(
+            canister: Principal,
+            method: &str,
+            events: Vec<Event>,
+        ) -> Result<(), String> {
+            if method == "unreachable" {
+                return Err("DestinationInvalid".to_string());
+            }
+            NOTIFIED.with(|notified| {
+                notified
+                    .borrow_mut()
+                    .push((canister, events.iter().map(|event| event.seq).collect()))
+            });
+            Ok(())
+        }
+    }
+
+    // Answers every webhook with WEBHOOK_RESPONSE_STATUS, recording what was posted
+    impl HttpOutcallManagerTrait for HttpOutcallManager {
+        async fn post_json(
+            url: String,
+            headers: Vec<HttpHeader>,
+            body: Vec<u8>,
+        ) -> CallResult<(HttpResponse,)> {
+            WEBHOOK_REQUESTS.with(|requests| requests.borrow_mut().push((url, headers, body)));
+            Ok((HttpResponse {
+                status: WEBHOOK_RESPONSE_STATUS
+                    .with(|status| *status.borrow())
+                    .into(),
+                ..Default::default()
+            },))
+        }
+    }
+
+    type PostedRequest = (String, Vec<HttpHeader>, Vec<u8>);
+
+    thread_local! {
+        static NOTIFIED: RefCell<Vec<(Principal, Vec<u64>)>> = RefCell::default();
+        static WEBHOOK_REQUESTS: RefCell<Vec<PostedRequest>> = RefCell::default();
+        static WEBHOOK_RESPONSE_STATUS: RefCell<u64> = const { RefCell::new(200) };
+        static SPAWNED: RefCell<u64> = const { RefCell::new(0) };
+    }
+
+    // The outcall stand-ins complete immediately, so a single poll runs the future to the end
+    fn run_to_completion<F: Future<Output = ()>>(future: F) {
+        let mut future = std::pin::pin!(future);
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        assert!(future.as_mut().poll(&mut context).is_ready());
     }
 
+    // Spawned futures never run; tests drive them with run_to_completion instead
     impl IcCdkSpawnManagerTrait for IcCdkSpawnManager {
-        fn run<F: 'static + Future<Output = ()>>(_future: F) {}
+        fn run<F: 'static + Future<Output = ()>>(_future: F) {
+            SPAWNED.with(|spawned| *spawned.borrow_mut() += 1);
+        }
     }
 
     fn vec_to_array(vec_to_convert: Vec<u8>) -> [u8; 32] {
@@ -438,6 +502,11 @@ mod tests {
                     })),
                     created_at_time: Timestamp { timestamp_nanos: 0 },
                     sweep_status: SweepStatus::NotSwept,
+                    flag: None,
+                    block_timestamp: None,
+                    parent_hash: None,
+                    block_hash: None,
+                    direction: None,
                 },
             );
         });
@@ -455,7 +524,7 @@ mod tests {
         refund_setup();
 
         // Your refund test logic for a valid transaction
-        let result = refund(1);
+        let result = refund(1, None);
         assert!(
             result.is_ok(),
             "Refund should succeed for a valid transaction"
@@ -472,7 +541,7 @@ mod tests {
             let _ = principal_ref.borrow_mut().set(StoredPrincipal::default());
         });
 
-        let result = refund(1);
+        let result = refund(1, None);
         assert!(
             result.is_err(),
             "Refund should fail if the principal is not set"
@@ -486,7 +555,7 @@ mod tests {
         refund_setup();
 
         // Attempt to refund a transaction that doesn't exist
-        let result = refund(999); // Assuming transaction with index 999 does not exist
+        let result = refund(999, None); // Assuming transaction with index 999 does not exist
         assert!(
             result.is_err(),
             "Refund should fail for a non-existent transaction"
@@ -553,6 +622,11 @@ mod tests {
                     })),
                     created_at_time: Timestamp { timestamp_nanos: 0 },
                     sweep_status: SweepStatus::NotSwept,
+                    flag: None,
+                    block_timestamp: None,
+                    parent_hash: None,
+                    block_hash: None,
+                    direction: None,
                 },
             );
             transactions.insert(
@@ -564,6 +638,11 @@ mod tests {
                     operation: None, // Operation that should not be swept
                     created_at_time: Timestamp { timestamp_nanos: 0 },
                     sweep_status: SweepStatus::Swept,
+                    flag: None,
+                    block_timestamp: None,
+                    parent_hash: None,
+                    block_hash: None,
+                    direction: None,
                 },
             );
         });
@@ -660,6 +739,11 @@ mod tests {
                     })),
                     created_at_time: Timestamp { timestamp_nanos: 0 },
                     sweep_status: SweepStatus::NotSwept,
+                    flag: None,
+                    block_timestamp: None,
+                    parent_hash: None,
+                    block_hash: None,
+                    direction: None,
                 },
             );
         });
@@ -699,4 +783,2453 @@ mod tests {
 
         teardown_sweep_environment();
     }
+
+    fn setup_subaccounts() {
+        CUSTODIAN_PRINCIPAL.with(|cp| {
+            let _ = cp.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+        let _ = LAST_SUBACCOUNT_NONCE.with(|nonce_ref| nonce_ref.borrow_mut().set(0));
+
+        add_subaccount(Some("alice".to_string()));
+        add_subaccount(None);
+        add_subaccount(Some("bob".to_string()));
+    }
+
+    fn teardown_subaccounts() {
+        SUBACCOUNTS.with(|s| s.borrow_mut().clear_new());
+        TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        ACCOUNT_TRANSACTIONS.with(|i| i.borrow_mut().clear_new());
+        teardown();
+    }
+
+    #[test]
+    fn list_subaccounts_paginates_with_cursor() {
+        setup_subaccounts();
+
+        let page = list_subaccounts(ListSubaccountsRequest {
+            limit: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(page.subaccounts.len(), 2);
+        assert_eq!(page.next_cursor, Some(1));
+
+        let page = list_subaccounts(ListSubaccountsRequest {
+            start_after: page.next_cursor,
+            limit: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(page.subaccounts.len(), 1);
+        assert_eq!(page.subaccounts[0].label, Some("bob".to_string()));
+        assert_eq!(page.next_cursor, None);
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn list_subaccounts_stops_a_sparse_scan_with_a_cursor() {
+        setup_subaccounts();
+        let last = MAX_SCAN as u64 + 3;
+        SUBACCOUNTS.with(|s| {
+            let mut subaccounts = s.borrow_mut();
+            for index in 3..last {
+                subaccounts.insert(index, StoredSubaccount::new(None));
+            }
+            subaccounts.insert(last, StoredSubaccount::new(Some("zed".to_string())));
+        });
+
+        let request = |start_after| ListSubaccountsRequest {
+            start_after,
+            label: Some("zed".to_string()),
+            ..Default::default()
+        };
+        let page = list_subaccounts(request(None));
+        assert!(page.subaccounts.is_empty());
+        assert_eq!(page.next_cursor, Some(MAX_SCAN as u64 - 1));
+        let page = list_subaccounts(request(page.next_cursor));
+        assert_eq!(page.subaccounts.len(), 1);
+        assert_eq!(page.subaccounts[0].index, last);
+        assert_eq!(page.next_cursor, None);
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn list_subaccounts_filters_by_label_and_unswept() {
+        setup_subaccounts();
+
+        let page = list_subaccounts(ListSubaccountsRequest {
+            label: Some("alice".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(page.subaccounts.len(), 1);
+        assert_eq!(page.subaccounts[0].index, 0);
+
+        // Deposit into the second subaccount
+        let to = from_hex(&get_subaccountid(1).unwrap()).unwrap().to_vec();
+        TRANSACTIONS.with(|t| {
+            let transaction = StoredTransactions {
+                index: 1,
+                memo: 0,
+                icrc1_memo: None,
+                operation: Some(Operation::Transfer(Transfer {
+                    to,
+                    fee: E8s { e8s: 100 },
+                    from: vec![2u8; 32],
+                    amount: E8s { e8s: 1000 },
+                    spender: None,
+                })),
+                created_at_time: Timestamp { timestamp_nanos: 0 },
+                sweep_status: SweepStatus::NotSwept,
+                flag: None,
+                block_timestamp: None,
+                parent_hash: None,
+                block_hash: None,
+                direction: None,
+            };
+            aggregate_deposit(&transaction);
+            t.borrow_mut().insert(1, transaction);
+        });
+
+        let page = list_subaccounts(ListSubaccountsRequest {
+            has_unswept: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(page.subaccounts.len(), 1);
+        assert_eq!(page.subaccounts[0].index, 1);
+        assert_eq!(page.subaccounts[0].deposits.total_received_e8s, 1000);
+        assert_eq!(page.subaccounts[0].deposits.pending_e8s, 1000);
+
+        let page = list_subaccounts(ListSubaccountsRequest {
+            has_unswept: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(page.subaccounts.len(), 2);
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn set_subaccount_status_flags_new_deposits() {
+        setup_subaccounts();
+
+        assert_eq!(
+            set_subaccount_status(1, SubaccountStatus::Disabled).unwrap(),
+            SubaccountStatus::Disabled
+        );
+        assert!(set_subaccount_status(99, SubaccountStatus::Disabled).is_err());
+
+        let to = from_hex(&get_subaccountid(1).unwrap()).unwrap().to_vec();
+        let operation = Operation::Transfer(Transfer {
+            to,
+            fee: E8s { e8s: 100 },
+            from: vec![2u8; 32],
+            amount: E8s { e8s: 1000 },
+            spender: None,
+        });
+        assert_eq!(
+            deposit_flag(&operation),
+            Some(TransactionFlag::SubaccountDisabled)
+        );
+
+        set_subaccount_status(1, SubaccountStatus::Archived).unwrap();
+        assert_eq!(
+            deposit_flag(&operation),
+            Some(TransactionFlag::SubaccountArchived)
+        );
+
+        set_subaccount_status(1, SubaccountStatus::Active).unwrap();
+        assert_eq!(deposit_flag(&operation), None);
+
+        let page = list_subaccounts(ListSubaccountsRequest {
+            status: Some(SubaccountStatus::Active),
+            ..Default::default()
+        });
+        assert_eq!(page.subaccounts.len(), 3);
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn test_sweep_user_vault_skips_flagged_transactions() {
+        setup_sweep_environment();
+        TRANSACTIONS.with(|t| {
+            let mut transactions = t.borrow_mut();
+            let mut transaction = transactions.get(&1).unwrap();
+            transaction.flag = Some(TransactionFlag::SubaccountDisabled);
+            transactions.insert(1, transaction);
+        });
+
+        sweep_user_vault().unwrap();
+
+        TRANSACTIONS.with(|t| {
+            assert_eq!(
+                t.borrow().get(&1).unwrap().sweep_status,
+                SweepStatus::NotSwept,
+                "Flagged transactions should be left for review."
+            );
+        });
+
+        teardown_sweep_environment();
+    }
+
+    #[test]
+    fn watch_account_adds_to_match_set() {
+        let account_id = AccountIdentifier::new(&STATIC_PRINCIPAL, &Subaccount([7; 32])).to_hex();
+        let account_bytes = from_hex(&account_id).unwrap().to_vec();
+        let result = watch_account(
+            AccountInput::AccountId(account_id.clone()),
+            Some("hot wallet".to_string()),
+        );
+        assert_eq!(result.unwrap(), account_id);
+        assert!(includes_hash(&account_bytes));
+
+        let icrc1_account = Icrc1Account {
+            owner: *STATIC_PRINCIPAL,
+            subaccount: None,
+        };
+        let expected = AccountIdentifier::new(&STATIC_PRINCIPAL, &Subaccount([0; 32])).to_hex();
+        let result = watch_account(AccountInput::Icrc1(icrc1_account.clone()), None);
+        assert_eq!(result.unwrap(), expected);
+
+        let watched = list_watched_accounts();
+        assert_eq!(watched.len(), 2);
+        assert!(watched
+            .iter()
+            .any(|w| w.account_id == expected && w.icrc1_account == Some(icrc1_account.clone())));
+
+        let operation = Operation::Transfer(Transfer {
+            to: account_bytes,
+            fee: E8s { e8s: 100 },
+            from: vec![2u8; 32],
+            amount: E8s { e8s: 1000 },
+            spender: None,
+        });
+        assert_eq!(deposit_flag(&operation), Some(TransactionFlag::WatchOnly));
+
+        WATCHED_ACCOUNTS.with(|w| w.borrow_mut().clear_new());
+        WATCHED_ACCOUNT_HASHES.with(|w| w.borrow_mut().clear());
+    }
+
+    #[test]
+    fn watch_account_rejects_invalid_input() {
+        setup_subaccounts();
+
+        let managed = get_subaccountid(0).unwrap();
+        assert!(watch_account(AccountInput::AccountId(managed), None).is_err());
+        assert!(watch_account(AccountInput::AccountId("zz".to_string()), None).is_err());
+        assert!(watch_account(
+            AccountInput::Icrc1(Icrc1Account {
+                owner: *STATIC_PRINCIPAL,
+                subaccount: Some(vec![1u8; 31]),
+            }),
+            None
+        )
+        .is_err());
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn test_sweep_user_vault_skips_watched_accounts() {
+        setup_sweep_environment();
+        WATCHED_ACCOUNT_HASHES.with(|w| w.borrow_mut().insert([1u8; 32].to_u64_hash()));
+
+        sweep_user_vault().unwrap();
+        assert!(
+            refund(1, None).is_err(),
+            "Watched accounts should never be refunded."
+        );
+
+        TRANSACTIONS.with(|t| {
+            assert_eq!(
+                t.borrow().get(&1).unwrap().sweep_status,
+                SweepStatus::NotSwept
+            );
+        });
+
+        WATCHED_ACCOUNT_HASHES.with(|w| w.borrow_mut().clear());
+        teardown_sweep_environment();
+    }
+
+    #[test]
+    fn derive_subaccount_schemes() {
+        let stored = StoredSubaccount {
+            label: None,
+            status: SubaccountStatus::Active,
+            external_id: Some("user-42".to_string()),
+            owner: Some(*STATIC_PRINCIPAL),
+        };
+
+        let subaccount = derive_subaccount(1, &stored).unwrap();
+        assert_eq!(subaccount, to_subaccount(1));
+        assert!(derive_subaccount(u32::MAX as u64 + 1, &stored).is_err());
+
+        let _ = DERIVATION_SCHEME.with(|s| s.borrow_mut().set(DerivationScheme::Counter64));
+        let subaccount = derive_subaccount(u32::MAX as u64 + 1, &stored).unwrap();
+        assert_eq!(subaccount.0[24..32], [0, 0, 0, 1, 0, 0, 0, 0]);
+
+        let _ = DERIVATION_SCHEME.with(|s| {
+            s.borrow_mut().set(DerivationScheme::Hashed {
+                namespace: "shop".to_string(),
+            })
+        });
+        let first = derive_subaccount(0, &stored).unwrap();
+        let second = derive_subaccount(7, &stored).unwrap();
+        assert_eq!(
+            first, second,
+            "Hashed subaccounts should not depend on the index"
+        );
+        assert!(derive_subaccount(0, &StoredSubaccount::new(None)).is_err());
+
+        let _ = DERIVATION_SCHEME.with(|s| s.borrow_mut().set(DerivationScheme::PrincipalEmbedded));
+        let subaccount = derive_subaccount(0, &stored).unwrap();
+        let bytes = STATIC_PRINCIPAL.as_slice();
+        assert_eq!(subaccount.0[0] as usize, bytes.len());
+        assert_eq!(&subaccount.0[1..1 + bytes.len()], bytes);
+
+        let _ = DERIVATION_SCHEME.with(|s| s.borrow_mut().set(DerivationScheme::default()));
+    }
+
+    #[test]
+    fn create_subaccount_with_hashed_scheme_is_deterministic() {
+        CUSTODIAN_PRINCIPAL.with(|cp| {
+            let _ = cp.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+        let _ = DERIVATION_SCHEME.with(|s| {
+            s.borrow_mut().set(DerivationScheme::Hashed {
+                namespace: "shop".to_string(),
+            })
+        });
+
+        let req = CreateSubaccountRequest {
+            external_id: Some("user-42".to_string()),
+            ..Default::default()
+        };
+        let first = create_subaccount(req.clone()).unwrap();
+        let second = create_subaccount(req).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(get_subaccount_count(), 1);
+
+        let other = create_subaccount(CreateSubaccountRequest {
+            external_id: Some("user-43".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_ne!(first, other);
+        assert!(create_subaccount(CreateSubaccountRequest::default()).is_err());
+
+        // Upgrades rebuild the same addresses from the registry
+        teardown();
+        reconstruct_subaccounts();
+        assert_eq!(get_subaccount_count(), 2);
+        assert!(includes_hash(&from_hex(&other).unwrap().to_vec()));
+
+        let _ = DERIVATION_SCHEME.with(|s| s.borrow_mut().set(DerivationScheme::default()));
+        teardown_subaccounts();
+    }
+
+    const ICRC1_TEXT: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+
+    #[test]
+    fn parse_account_identifier_verifies_checksum() {
+        let account_id = AccountIdentifier::new(&STATIC_PRINCIPAL, &Subaccount([0; 32])).to_hex();
+        assert!(parse_account_identifier(&account_id).is_ok());
+
+        let mut tampered = account_id.clone();
+        tampered.replace_range(
+            62..64,
+            if &account_id[62..64] == "00" {
+                "01"
+            } else {
+                "00"
+            },
+        );
+        assert!(matches!(
+            parse_account_identifier(&tampered),
+            Err(AddressError::ChecksumMismatch { .. })
+        ));
+
+        assert_eq!(
+            parse_account_identifier("zz"),
+            Err(AddressError::InvalidHex)
+        );
+        assert_eq!(
+            parse_account_identifier("00"),
+            Err(AddressError::InvalidLength {
+                expected: 32,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn parse_icrc1_account_textual_encoding() {
+        let account = parse_icrc1_account(ICRC1_TEXT).unwrap();
+        assert_eq!(
+            account.owner,
+            Principal::from_text("k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae")
+                .unwrap()
+        );
+        assert_eq!(account.subaccount, Some((1..=32).collect::<Vec<u8>>()));
+
+        let default_account = parse_icrc1_account("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        assert_eq!(default_account.subaccount, None);
+
+        let bad_checksum = ICRC1_TEXT.replace("dfxgiyy", "dfxgiya");
+        assert!(matches!(
+            parse_icrc1_account(&bad_checksum),
+            Err(AddressError::InvalidIcrc1Checksum { .. })
+        ));
+
+        let non_canonical = ICRC1_TEXT.replace(".1020", ".01020");
+        assert_eq!(
+            parse_icrc1_account(&non_canonical),
+            Err(AddressError::NonCanonicalSubaccount)
+        );
+    }
+
+    #[test]
+    fn parse_address_accepts_both_forms() {
+        let parsed = parse_address(ICRC1_TEXT).unwrap();
+        let account = parsed.icrc1_account.clone().unwrap();
+        assert_eq!(
+            parsed.account_id,
+            icrc1_to_account_identifier(&account).unwrap()
+        );
+
+        let hex = hex::encode(parsed.account_id);
+        let parsed_hex = parse_address(&hex).unwrap();
+        assert_eq!(parsed_hex.account_id, parsed.account_id);
+        assert_eq!(parsed_hex.icrc1_account, None);
+
+        assert!(watch_account(AccountInput::Text(ICRC1_TEXT.to_string()), None).is_ok());
+        assert!(includes_hash(&parsed.account_id.to_vec()));
+        WATCHED_ACCOUNTS.with(|w| w.borrow_mut().clear_new());
+        WATCHED_ACCOUNT_HASHES.with(|w| w.borrow_mut().clear());
+    }
+
+    #[test]
+    fn test_refund_rejects_invalid_destination() {
+        refund_setup();
+
+        let result = refund(1, Some("not-an-account".to_string()));
+        assert!(
+            result.is_err(),
+            "Refund should fail for an invalid destination"
+        );
+
+        let result = refund(1, Some(ICRC1_TEXT.to_string()));
+        assert!(result.is_ok(), "Refund should accept an ICRC-1 destination");
+
+        refund_teardown();
+    }
+
+    #[test]
+    fn list_transactions_returns_newest_entries() {
+        populate_transactions(150, None);
+
+        let transactions = list_transactions(Some(10));
+        let indices: Vec<u64> = transactions.iter().map(|t| t.index).collect();
+        assert_eq!(indices, (141..=150).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn list_transactions_page_walks_both_directions() {
+        populate_transactions(10, None);
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = list_transactions_page(ListTransactionsRequest {
+                start_after: cursor,
+                limit: Some(3),
+                direction: None,
+            });
+            seen.extend(page.transactions.iter().map(|t| t.index));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, (1..=10).collect::<Vec<u64>>());
+
+        let page = list_transactions_page(ListTransactionsRequest {
+            start_after: None,
+            limit: Some(4),
+            direction: Some(SortDirection::Descending),
+        });
+        let indices: Vec<u64> = page.transactions.iter().map(|t| t.index).collect();
+        assert_eq!(indices, vec![10, 9, 8, 7]);
+        assert_eq!(page.next_cursor, Some(7));
+
+        let page = list_transactions_page(ListTransactionsRequest {
+            start_after: page.next_cursor,
+            limit: Some(10),
+            direction: Some(SortDirection::Descending),
+        });
+        let indices: Vec<u64> = page.transactions.iter().map(|t| t.index).collect();
+        assert_eq!(indices, vec![6, 5, 4, 3, 2, 1]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn list_transactions_for_account_uses_account_index() {
+        setup_subaccounts();
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap();
+        let bob = from_hex(&get_subaccountid(2).unwrap()).unwrap();
+        TRANSACTIONS.with(|t| {
+            let mut transactions = t.borrow_mut();
+            for i in 1..=6u64 {
+                let to = if i % 2 == 0 { bob } else { alice };
+                let transaction = StoredTransactions {
+                    index: i,
+                    memo: i,
+                    icrc1_memo: None,
+                    operation: Some(Operation::Transfer(Transfer {
+                        to: to.to_vec(),
+                        fee: E8s { e8s: 100 },
+                        from: vec![2u8; 32],
+                        amount: E8s { e8s: 1000 },
+                        spender: None,
+                    })),
+                    created_at_time: Timestamp { timestamp_nanos: 0 },
+                    sweep_status: SweepStatus::NotSwept,
+                    flag: None,
+                    block_timestamp: None,
+                    parent_hash: None,
+                    block_hash: None,
+                    direction: None,
+                };
+                index_transaction(&transaction);
+                transactions.insert(i, transaction);
+            }
+        });
+
+        let page = list_transactions_for_account(AccountTransactionsRequest {
+            account: AccountQuery::Nonce(2),
+            start_after: None,
+            limit: Some(2),
+            direction: None,
+        })
+        .unwrap();
+        let indices: Vec<u64> = page.transactions.iter().map(|t| t.index).collect();
+        assert_eq!(indices, vec![2, 4]);
+        assert_eq!(page.next_cursor, Some(4));
+
+        let page = list_transactions_for_account(AccountTransactionsRequest {
+            account: AccountQuery::Address(hex::encode(alice)),
+            start_after: None,
+            limit: None,
+            direction: Some(SortDirection::Descending),
+        })
+        .unwrap();
+        let indices: Vec<u64> = page.transactions.iter().map(|t| t.index).collect();
+        assert_eq!(indices, vec![5, 3, 1]);
+        assert_eq!(page.next_cursor, None);
+
+        // The sender is not a managed account, so it is not indexed
+        let page = list_transactions_for_account(AccountTransactionsRequest {
+            account: AccountQuery::Nonce(1),
+            start_after: None,
+            limit: None,
+            direction: None,
+        })
+        .unwrap();
+        assert!(page.transactions.is_empty());
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn query_transactions_combines_filters() {
+        TRANSACTIONS.with(|t| {
+            let mut transactions = t.borrow_mut();
+            for i in 1..=10u64 {
+                let operation = if i % 2 == 0 {
+                    Operation::Transfer(Transfer {
+                        to: vec![1u8; 32],
+                        fee: E8s { e8s: 100 },
+                        from: vec![2u8; 32],
+                        amount: E8s { e8s: i * 1000 },
+                        spender: None,
+                    })
+                } else {
+                    Operation::Mint(Mint {
+                        to: vec![1u8; 32],
+                        amount: E8s { e8s: i * 1000 },
+                    })
+                };
+                let mut transaction = StoredTransactions::new(
+                    i,
+                    Transaction {
+                        memo: i % 3,
+                        icrc1_memo: None,
+                        operation: Some(operation),
+                        created_at_time: Timestamp::from_nanos(i * 10),
+                    },
+                );
+                if i == 4 {
+                    transaction.sweep_status = SweepStatus::Swept;
+                }
+                transactions.insert(i, transaction);
+            }
+        });
+
+        let indices = |page: TransactionsPage| -> Vec<u64> {
+            page.transactions.iter().map(|t| t.index).collect()
+        };
+
+        let page = query_transactions(TransactionFilter {
+            operation: Some(OperationKind::Transfer),
+            min_amount: Some(3000),
+            max_amount: Some(8000),
+            ..Default::default()
+        });
+        assert_eq!(indices(page), vec![4, 6, 8]);
+
+        let page = query_transactions(TransactionFilter {
+            operation: Some(OperationKind::Transfer),
+            sweep_status: Some(SweepStatus::NotSwept),
+            from_timestamp: Some(Timestamp::from_nanos(30)),
+            to_timestamp: Some(Timestamp::from_nanos(90)),
+            ..Default::default()
+        });
+        assert_eq!(indices(page), vec![6, 8]);
+
+        let page = query_transactions(TransactionFilter {
+            memo: Some(0),
+            from_block: Some(2),
+            to_block: Some(9),
+            ..Default::default()
+        });
+        assert_eq!(indices(page), vec![3, 6, 9]);
+
+        // Descending pages resume strictly before the cursor
+        let page = query_transactions(TransactionFilter {
+            operation: Some(OperationKind::Mint),
+            limit: Some(2),
+            direction: Some(SortDirection::Descending),
+            ..Default::default()
+        });
+        assert_eq!(page.next_cursor, Some(7));
+        assert_eq!(indices(page), vec![9, 7]);
+
+        let page = query_transactions(TransactionFilter {
+            operation: Some(OperationKind::Mint),
+            start_after: Some(7),
+            direction: Some(SortDirection::Descending),
+            ..Default::default()
+        });
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(indices(page), vec![5, 3, 1]);
+    }
+
+    fn deposit_block(to: Vec<u8>, timestamp_nanos: u64, parent_hash: Option<Vec<u8>>) -> Block {
+        Block {
+            transaction: Transaction {
+                memo: 0,
+                icrc1_memo: None,
+                operation: Some(Operation::Transfer(Transfer {
+                    to,
+                    fee: E8s { e8s: 100 },
+                    from: vec![2u8; 32],
+                    amount: E8s { e8s: 1000 },
+                    spender: None,
+                })),
+                created_at_time: Timestamp::from_nanos(0),
+            },
+            timestamp: Timestamp::from_nanos(timestamp_nanos),
+            parent_hash,
+        }
+    }
+
+    // Expected bytes are written out field by field from the ledger's types.proto
+    // (ic_ledger.pb.v1), independently of chain.rs
+    #[test]
+    fn block_encoding_matches_the_ledger_protobuf_schema() {
+        let account = |byte: u8| vec![byte; 32];
+        let hex_account = |byte: u8| format!("0a20{}", format!("{:02x}", byte).repeat(32));
+        let block = |parent_hash: Option<Vec<u8>>, transaction: Transaction| Block {
+            transaction,
+            timestamp: Timestamp::from_nanos(1),
+            parent_hash,
+        };
+        let transaction = |memo: u64, created_at_time: u64, operation: Operation| Transaction {
+            memo,
+            icrc1_memo: None,
+            operation: Some(operation),
+            created_at_time: Timestamp::from_nanos(created_at_time),
+        };
+        let encode = |block: &Block, created_at_time: Option<u64>| {
+            hex::encode(chain::encode_block(block, created_at_time))
+        };
+
+        // Transfer with parent hash, memo and created_at_time; fee 10000 is a two-byte varint
+        let transfer = block(
+            Some(vec![0xaa; 32]),
+            transaction(
+                2,
+                3,
+                Operation::Transfer(Transfer {
+                    to: account(0x22),
+                    fee: E8s { e8s: 10_000 },
+                    from: account(0x11),
+                    amount: E8s { e8s: 5 },
+                    spender: None,
+                }),
+            ),
+        );
+        let send = format!(
+            "0a22{}1222{}1a020805220308904e",
+            hex_account(0x11),
+            hex_account(0x22)
+        );
+        assert_eq!(
+            encode(&transfer, Some(3)),
+            format!(
+                "0a22{}120208011a5b2202080232020803 1a51{}",
+                hex_account(0xaa),
+                send
+            )
+            .replace(' ', "")
+        );
+
+        // Transfer from an allowance with an ICRC-1 memo and no created_at_time, no parent
+        // (the first block) and a zero memo; the transaction length needs two bytes
+        let mut transfer_from = block(
+            None,
+            transaction(
+                0,
+                1,
+                Operation::Transfer(Transfer {
+                    to: account(0x22),
+                    fee: E8s { e8s: 10_000 },
+                    from: account(0x11),
+                    amount: E8s { e8s: 5 },
+                    spender: Some(account(0x33)),
+                }),
+            ),
+        );
+        transfer_from.transaction.icrc1_memo = Some(vec![0xde, 0xad]);
+        assert_eq!(
+            encode(&transfer_from, None),
+            format!(
+                "120208011a8101 2200 3a040a02dead 1a77{}3224 0a22{}",
+                send,
+                hex_account(0x33)
+            )
+            .replace(' ', "")
+        );
+        assert_eq!(chain::block_hashes(&transfer_from).len(), 2);
+
+        // Mint of 1 ICP
+        let mint = block(
+            Some(vec![0xbb; 32]),
+            transaction(
+                0,
+                5,
+                Operation::Mint(Mint {
+                    to: account(0x22),
+                    amount: E8s { e8s: 100_000_000 },
+                }),
+            ),
+        );
+        assert_eq!(
+            encode(&mint, Some(5)),
+            format!(
+                "0a22{}120208011a33 2200 32020805 122b 1222{}1a050880c2d72f",
+                hex_account(0xbb),
+                hex_account(0x22)
+            )
+            .replace(' ', "")
+        );
+
+        // Burn through an allowance
+        let burn = block(
+            None,
+            transaction(
+                0,
+                1,
+                Operation::Burn(Burn {
+                    from: account(0x11),
+                    amount: E8s { e8s: 7 },
+                    spender: Some(account(0x33)),
+                }),
+            ),
+        );
+        assert_eq!(
+            encode(&burn, None),
+            format!(
+                "120208011a50 2200 0a4c 0a22{}1a020807 2222{}",
+                hex_account(0x11),
+                hex_account(0x33)
+            )
+            .replace(' ', "")
+        );
+
+        // Approve is a send of zero tokens carrying the allowance
+        let approve = block(
+            None,
+            transaction(
+                0,
+                1,
+                Operation::Approve(Approve {
+                    fee: E8s { e8s: 10_000 },
+                    from: account(0x11),
+                    allowance_e8s: 50,
+                    allowance: E8s { e8s: 50 },
+                    expected_allowance: None,
+                    expires_at: Some(Timestamp::from_nanos(9)),
+                    spender: account(0x33),
+                }),
+            ),
+        );
+        assert_eq!(
+            encode(&approve, None),
+            format!(
+                "120208011a5d 2200 1a59 0a22{}1222{}1a00220308904e 2a080a02083212020809",
+                hex_account(0x11),
+                hex_account(0x33)
+            )
+            .replace(' ', "")
+        );
+    }
+
+    #[test]
+    fn store_blocks_keeps_ledger_timestamp_and_hash() {
+        setup_subaccounts();
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let blocks = vec![
+            deposit_block(alice.clone(), 500, Some(vec![4u8; 32])),
+            deposit_block(vec![9u8; 32], 600, Some(vec![5u8; 32])),
+            deposit_block(alice.clone(), 700, Some(vec![6u8; 32])),
+        ];
+        assert_eq!(store_blocks(5, &blocks), 8);
+
+        let first = TRANSACTIONS.with(|t| t.borrow().get(&5)).unwrap();
+        assert_eq!(first.block_timestamp, Some(Timestamp::from_nanos(500)));
+        assert_eq!(first.parent_hash, Some(vec![4u8; 32]));
+        assert_eq!(
+            first.block_hash,
+            Some(chain::block_hashes(&blocks[0])[0].clone())
+        );
+        assert!(TRANSACTIONS.with(|t| t.borrow().get(&6)).is_none());
+        let last = TRANSACTIONS.with(|t| t.borrow().get(&7)).unwrap();
+        assert_eq!(
+            last.block_hash,
+            Some(chain::block_hashes(&blocks[2])[0].clone())
+        );
+
+        // A block without created_at_time has two candidate hashes until its successor names
+        // one of them
+        let ambiguous = deposit_block(alice.clone(), 0, Some(vec![7u8; 32]));
+        let candidates = chain::block_hashes(&ambiguous);
+        assert_eq!(candidates.len(), 2);
+        store_blocks(8, &[ambiguous]);
+        let stored = || TRANSACTIONS.with(|t| t.borrow().get(&8)).unwrap();
+        assert_eq!(stored().block_hash, None);
+        store_blocks(9, &[deposit_block(vec![9u8; 32], 900, Some(vec![8u8; 32]))]);
+        assert_eq!(stored().block_hash, None);
+        store_blocks(
+            9,
+            &[deposit_block(
+                vec![9u8; 32],
+                900,
+                Some(candidates[1].clone()),
+            )],
+        );
+        assert_eq!(stored().block_hash, Some(candidates[1].clone()));
+
+        // Clearing by time uses the ledger timestamp, not created_at_time
+        let cleared =
+            clear_transactions(None, Some(Timestamp::from_nanos(600)), None, Some(true)).unwrap();
+        assert_eq!(cleared.remaining, 1);
+        assert!(TRANSACTIONS.with(|t| t.borrow().contains_key(&7)));
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn store_blocks_tags_direction() {
+        setup_subaccounts();
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let bob = from_hex(&get_subaccountid(2).unwrap()).unwrap().to_vec();
+        let transfer = |from: &Vec<u8>, to: &Vec<u8>| {
+            let mut block = deposit_block(to.clone(), 0, None);
+            if let Some(Operation::Transfer(data)) = &mut block.transaction.operation {
+                data.from = from.clone();
+            }
+            block
+        };
+        let external = vec![9u8; 32];
+        let blocks = vec![
+            transfer(&external, &alice),
+            transfer(&alice, &external),
+            transfer(&alice, &bob),
+            transfer(&external, &external),
+        ];
+        store_blocks(1, &blocks);
+
+        let direction = |index: u64| {
+            TRANSACTIONS
+                .with(|t| t.borrow().get(&index))
+                .map(|t| t.direction)
+        };
+        assert_eq!(direction(1), Some(Some(TransactionDirection::Inbound)));
+        assert_eq!(direction(2), Some(Some(TransactionDirection::Outbound)));
+        assert_eq!(direction(3), Some(Some(TransactionDirection::Internal)));
+        assert_eq!(direction(4), None);
+
+        PRINCIPAL.with(|p| {
+            let _ = p.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+        sweep_user_vault().unwrap();
+        let sweep_status =
+            |index: u64| TRANSACTIONS.with(|t| t.borrow().get(&index).unwrap().sweep_status);
+        assert_eq!(sweep_status(1), SweepStatus::Swept);
+        assert_eq!(sweep_status(2), SweepStatus::NotSwept);
+        assert_eq!(sweep_status(3), SweepStatus::NotSwept);
+        assert!(refund(2, None).is_err());
+
+        PRINCIPAL.with(|p| {
+            let _ = p.borrow_mut().set(StoredPrincipal::default());
+        });
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn stored_transactions_decode_legacy_and_enveloped_records() {
+        let transaction = StoredTransactions::new(
+            7,
+            Transaction {
+                memo: 1,
+                icrc1_memo: Some(vec![0u8; 32]),
+                operation: None,
+                created_at_time: Timestamp::from_nanos(5),
+            },
+        );
+
+        let enveloped = transaction.to_bytes();
+        assert_eq!(
+            StoredTransactions::schema_version(&enveloped),
+            Some(TRANSACTION_SCHEMA_VERSION)
+        );
+        assert_eq!(StoredTransactions::from_bytes(enveloped).index, 7);
+
+        let legacy = candid::encode_one(&transaction).unwrap();
+        assert_eq!(StoredTransactions::schema_version(&legacy), Some(0));
+        let decoded = StoredTransactions::from_bytes(std::borrow::Cow::Owned(legacy));
+        assert_eq!(decoded.index, 7);
+        assert_eq!(decoded.icrc1_memo, Some(vec![0u8; 32]));
+    }
+
+    #[test]
+    fn migrate_transactions_batch_advances_cursor_until_done() {
+        populate_transactions(1200, None);
+        MIGRATION_STATE.with(|s| {
+            let _ = s.borrow_mut().set(MigrationState::default());
+        });
+
+        migrate_transactions_batch();
+        assert_eq!(
+            get_migration_state(),
+            MigrationState {
+                schema_version: 0,
+                cursor: Some(500),
+            }
+        );
+
+        migrate_transactions_batch();
+        assert_eq!(get_migration_state().cursor, Some(1000));
+
+        migrate_transactions_batch();
+        assert_eq!(
+            get_migration_state(),
+            MigrationState {
+                schema_version: TRANSACTION_SCHEMA_VERSION,
+                cursor: None,
+            }
+        );
+        assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 1200);
+    }
+
+    fn synthetic_transaction(i: u64) -> StoredTransactions {
+        let account = |seed: u64| -> Vec<u8> {
+            let mut account = [0u8; 32];
+            account[..8].copy_from_slice(&seed.to_be_bytes());
+            account.to_vec()
+        };
+        let operation = match i % 4 {
+            0 => Operation::Approve(Approve {
+                fee: E8s { e8s: 10_000 },
+                from: account(i),
+                allowance_e8s: -(i as i64),
+                allowance: E8s { e8s: i },
+                expected_allowance: Some(E8s { e8s: 1 }),
+                expires_at: None,
+                spender: account(i + 1),
+            }),
+            1 => Operation::Burn(Burn {
+                from: account(i),
+                amount: E8s { e8s: i * 7 },
+                spender: None,
+            }),
+            2 => Operation::Mint(Mint {
+                to: account(i),
+                amount: E8s { e8s: u64::MAX },
+            }),
+            _ => Operation::Transfer(Transfer {
+                to: account(i),
+                fee: E8s { e8s: 10_000 },
+                from: account(i + 1),
+                amount: E8s { e8s: i * 100_000 },
+                spender: Some(account(i + 2)),
+            }),
+        };
+
+        let mut transaction = StoredTransactions::from_block(
+            i,
+            Block {
+                transaction: Transaction {
+                    memo: i,
+                    icrc1_memo: Some(vec![7u8; 32]),
+                    operation: Some(operation),
+                    created_at_time: Timestamp::from_nanos(1_700_000_000_000_000_000 + i),
+                },
+                timestamp: Timestamp::from_nanos(1_700_000_000_000_000_000 + i),
+                parent_hash: Some(vec![1u8; 32]),
+            },
+        );
+        transaction.block_hash = Some(vec![2u8; 32]);
+        transaction.direction = Some(TransactionDirection::Internal);
+        transaction.flag = Some(TransactionFlag::WatchOnly);
+        transaction
+    }
+
+    #[test]
+    fn compact_encoding_round_trips_every_operation() {
+        for i in 0..4 {
+            let transaction = synthetic_transaction(i);
+            let decoded = StoredTransactions::from_bytes(transaction.to_bytes());
+            assert_eq!(
+                candid::encode_one(&decoded).unwrap(),
+                candid::encode_one(&transaction).unwrap()
+            );
+        }
+
+        let mut legacy = vec![1u8];
+        legacy.extend(candid::encode_one(synthetic_transaction(3)).unwrap());
+        let decoded = StoredTransactions::from_bytes(std::borrow::Cow::Owned(legacy));
+        assert_eq!(decoded.index, 3);
+
+        assert!(matches!(
+            crate::encoding::decode_transaction(&[1, 2]),
+            Err(crate::encoding::DecodeError::UnexpectedEnd)
+        ));
+    }
+
+    // Run with --nocapture to see the numbers
+    #[test]
+    fn benchmark_compact_encoding_against_candid() {
+        use std::time::Instant;
+
+        let dataset: Vec<StoredTransactions> = (0..5_000).map(synthetic_transaction).collect();
+
+        let started = Instant::now();
+        let candid: Vec<Vec<u8>> = dataset
+            .iter()
+            .map(|t| candid::encode_one(t).unwrap())
+            .collect();
+        let candid_encode = started.elapsed();
+        let started = Instant::now();
+        candid.iter().for_each(|bytes| {
+            candid::decode_one::<StoredTransactions>(bytes).unwrap();
+        });
+        let candid_decode = started.elapsed();
+
+        let started = Instant::now();
+        let compact: Vec<Vec<u8>> = dataset.iter().map(|t| t.to_bytes().into_owned()).collect();
+        let compact_encode = started.elapsed();
+        let started = Instant::now();
+        compact.iter().for_each(|bytes| {
+            StoredTransactions::from_bytes(std::borrow::Cow::Borrowed(bytes));
+        });
+        let compact_decode = started.elapsed();
+
+        let candid_bytes: usize = candid.iter().map(Vec::len).sum();
+        let compact_bytes: usize = compact.iter().map(Vec::len).sum();
+        println!(
+            "candid: {} bytes/record, encode {:?}, decode {:?}",
+            candid_bytes / dataset.len(),
+            candid_encode,
+            candid_decode
+        );
+        println!(
+            "compact: {} bytes/record, encode {:?}, decode {:?}",
+            compact_bytes / dataset.len(),
+            compact_encode,
+            compact_decode
+        );
+
+        assert!(compact_bytes * 2 < candid_bytes);
+    }
+
+    fn set_sweep_status(indices: std::ops::RangeInclusive<u64>, status: SweepStatus) {
+        TRANSACTIONS.with(|t| {
+            let mut transactions = t.borrow_mut();
+            for i in indices {
+                let mut transaction = transactions.get(&i).unwrap();
+                transaction.sweep_status = status.clone();
+                transactions.insert(i, transaction);
+            }
+        });
+    }
+
+    // Deposits into the subaccount added by setup()
+    fn populate_deposits(count: u64) {
+        setup();
+        populate_transactions(count, None);
+        TRANSACTIONS.with(|t| {
+            let mut transactions = t.borrow_mut();
+            for i in 1..=count {
+                let mut transaction = transactions.get(&i).unwrap();
+                transaction.operation = Some(Operation::Transfer(Transfer {
+                    to: vec![1u8; 32],
+                    fee: E8s { e8s: 100 },
+                    from: vec![2u8; 32],
+                    amount: E8s { e8s: 1000 },
+                    spender: None,
+                }));
+                transactions.insert(i, transaction);
+            }
+        });
+    }
+
+    fn teardown_retention() {
+        RETENTION_POLICY.with(|p| {
+            let _ = p.borrow_mut().set(RetentionPolicy::default());
+        });
+        AUDIT_LOG.with(|l| l.borrow_mut().clear_new());
+        TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+    }
+
+    #[test]
+    fn retention_by_count_keeps_unsettled_deposits() {
+        populate_deposits(10);
+        set_sweep_status(1..=4, SweepStatus::Swept);
+        set_sweep_status(5..=6, SweepStatus::Refunded);
+        set_retention_policy(RetentionPolicy {
+            max_count: Some(2),
+            ..Default::default()
+        });
+
+        assert!(prune_transactions(now_nanos()));
+        let remaining: Vec<u64> =
+            TRANSACTIONS.with(|t| t.borrow().iter().map(|(k, _)| k).collect());
+        assert_eq!(remaining, vec![7, 8, 9, 10]);
+
+        let log = get_audit_log(None, None);
+        assert_eq!(log.records.len(), 1);
+        assert_eq!(
+            log.records[0].event,
+            AuditEvent::RetentionRun {
+                pruned: 6,
+                kept_unsettled: 4,
+            }
+        );
+
+        teardown_retention();
+    }
+
+    #[test]
+    fn retention_by_age_respects_settled_only() {
+        // Timestamps are 1000ns, far older than any cutoff
+        populate_deposits(6);
+        set_sweep_status(1..=2, SweepStatus::Swept);
+        TRANSACTIONS.with(|t| {
+            let mut transactions = t.borrow_mut();
+            for i in 3..=4 {
+                let mut transaction = transactions.get(&i).unwrap();
+                transaction.direction = Some(TransactionDirection::Outbound);
+                transactions.insert(i, transaction);
+            }
+        });
+        set_retention_policy(RetentionPolicy {
+            max_age_seconds: Some(60),
+            settled_only: true,
+            ..Default::default()
+        });
+
+        prune_transactions(now_nanos());
+        let remaining: Vec<u64> =
+            TRANSACTIONS.with(|t| t.borrow().iter().map(|(k, _)| k).collect());
+        assert_eq!(remaining, vec![3, 4, 5, 6]);
+
+        // Outbound entries are not deposits, so they go once settled_only is off
+        set_retention_policy(RetentionPolicy {
+            max_age_seconds: Some(60),
+            settled_only: false,
+            ..Default::default()
+        });
+        prune_transactions(now_nanos());
+        let remaining: Vec<u64> =
+            TRANSACTIONS.with(|t| t.borrow().iter().map(|(k, _)| k).collect());
+        assert_eq!(remaining, vec![5, 6]);
+        assert_eq!(get_audit_log(None, Some(1)).next_cursor, Some(0));
+
+        teardown_retention();
+    }
+
+    #[test]
+    fn retention_resumes_from_cursor_across_batches() {
+        populate_transactions(1500, None);
+        set_sweep_status(1..=1500, SweepStatus::Swept);
+        set_retention_policy(RetentionPolicy {
+            max_age_seconds: Some(0),
+            ..Default::default()
+        });
+
+        assert!(!prune_transactions(now_nanos()));
+        assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 500);
+        assert!(get_audit_log(None, None).records.is_empty());
+        assert!(prune_transactions(now_nanos()));
+        assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 0);
+
+        // Each pass is summarised once, including passes that change nothing
+        assert!(prune_transactions(now_nanos()));
+        let events: Vec<AuditEvent> = get_audit_log(None, None)
+            .records
+            .into_iter()
+            .map(|record| record.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                AuditEvent::RetentionRun {
+                    pruned: 1500,
+                    kept_unsettled: 0,
+                },
+                AuditEvent::RetentionRun {
+                    pruned: 0,
+                    kept_unsettled: 0,
+                },
+            ]
+        );
+
+        teardown_retention();
+    }
+
+    #[test]
+    fn retention_prunes_watch_only_deposits_and_approvals() {
+        setup_subaccounts();
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let hot_wallet = AccountIdentifier::new(&STATIC_PRINCIPAL, &Subaccount([7; 32])).to_hex();
+        watch_account(AccountInput::AccountId(hot_wallet.clone()), None).unwrap();
+
+        let approval = Block {
+            transaction: Transaction {
+                memo: 0,
+                icrc1_memo: None,
+                operation: Some(Operation::Approve(Approve {
+                    fee: E8s { e8s: 100 },
+                    from: alice.clone(),
+                    allowance_e8s: 1000,
+                    allowance: E8s { e8s: 1000 },
+                    expected_allowance: None,
+                    expires_at: None,
+                    spender: vec![2u8; 32],
+                })),
+                created_at_time: Timestamp::from_nanos(0),
+            },
+            timestamp: Timestamp::from_nanos(300),
+            parent_hash: None,
+        };
+        store_blocks(
+            1,
+            &[
+                deposit_block(alice.clone(), 100, None),
+                deposit_block(from_hex(&hot_wallet).unwrap().to_vec(), 200, None),
+                approval,
+            ],
+        );
+        set_retention_policy(RetentionPolicy {
+            max_age_seconds: Some(60),
+            ..Default::default()
+        });
+
+        prune_transactions(now_nanos());
+        let remaining: Vec<u64> =
+            TRANSACTIONS.with(|t| t.borrow().iter().map(|(k, _)| k).collect());
+        assert_eq!(remaining, vec![1]);
+        assert_eq!(
+            get_audit_log(None, None).records[0].event,
+            AuditEvent::RetentionRun {
+                pruned: 2,
+                kept_unsettled: 1,
+            }
+        );
+
+        WATCHED_ACCOUNTS.with(|w| w.borrow_mut().clear_new());
+        teardown_retention();
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn refund_marks_transaction_refunded() {
+        refund_setup();
+
+        refund(1, None).unwrap();
+        let transaction = TRANSACTIONS.with(|t| t.borrow().get(&1)).unwrap();
+        assert_eq!(transaction.sweep_status, SweepStatus::Refunded);
+        assert!(refund(1, None).is_err());
+
+        refund_teardown();
+    }
+
+    #[test]
+    fn clear_transactions_keeps_unsettled_deposits_unless_forced() {
+        populate_deposits(10);
+        set_sweep_status(1..=3, SweepStatus::Swept);
+        // Not a deposit, so nothing is owed on it
+        TRANSACTIONS.with(|t| {
+            let mut transaction = t.borrow().get(&5).unwrap();
+            transaction.operation = None;
+            t.borrow_mut().insert(5, transaction);
+        });
+
+        let cleared = clear_transactions(Some(6), None, None, None).unwrap();
+        assert_eq!(
+            cleared,
+            ClearTransactionsResponse {
+                removed: 4,
+                kept_unsettled: 2,
+                remaining: 6,
+                next_cursor: None,
+            }
+        );
+        assert!(get_audit_log(None, None).records.is_empty());
+
+        let cleared = clear_transactions(Some(6), None, None, Some(true)).unwrap();
+        assert_eq!(cleared.removed, 2);
+        assert_eq!(cleared.remaining, 4);
+
+        let log = get_audit_log(None, None);
+        assert_eq!(log.records.len(), 1);
+        match &log.records[0].event {
+            AuditEvent::ForcedClear {
+                indices,
+                amount_e8s,
+                ..
+            } => {
+                assert_eq!(indices, &vec![4, 6]);
+                assert_eq!(*amount_e8s, 2000);
+            }
+            other => panic!("Unexpected audit event {:?}", other),
+        }
+
+        teardown_retention();
+    }
+
+    #[test]
+    fn clear_transactions_returns_cursor_when_budget_runs_out() {
+        populate_transactions(10_050, None);
+
+        let cleared = clear_transactions(Some(10_050), None, None, Some(true)).unwrap();
+        assert_eq!(cleared.removed, 10_000);
+        assert_eq!(cleared.next_cursor, Some(10_000));
+
+        let cleared =
+            clear_transactions(Some(10_050), None, cleared.next_cursor, Some(true)).unwrap();
+        assert_eq!(cleared.removed, 50);
+        assert_eq!(cleared.next_cursor, None);
+        assert_eq!(cleared.remaining, 0);
+
+        teardown_retention();
+    }
+
+    #[test]
+    fn export_transactions_as_csv_and_json() {
+        setup_subaccounts();
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let mut blocks: Vec<Block> = (0..3)
+            .map(|i| deposit_block(alice.clone(), 100 + i, None))
+            .collect();
+        blocks.push(deposit_block(vec![9u8; 32], 200, None));
+        store_blocks(1, &blocks);
+
+        let chunk = export_transactions(ExportRequest {
+            filter: TransactionFilter {
+                limit: Some(2),
+                ..Default::default()
+            },
+            format: ExportFormat::Csv,
+        });
+        let lines: Vec<&str> = chunk.data.lines().collect();
+        assert_eq!(lines[0], crate::export::CSV_HEADER);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(chunk.rows, 2);
+        assert_eq!(chunk.next_cursor, Some(2));
+
+        let alice_icrc1 = format_icrc1_account(&Icrc1Account {
+            owner: *STATIC_PRINCIPAL,
+            subaccount: Some(to_subaccount(0).0.to_vec()),
+        })
+        .unwrap();
+        let fields: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(fields[0], "1");
+        assert_eq!(fields[1], "100");
+        assert_eq!(fields[6], "0.00001000");
+        assert_eq!(fields[11], hex::encode(&alice));
+        assert_eq!(fields[12], alice_icrc1);
+        assert_eq!(fields[14], "NotSwept");
+        assert_eq!(fields[17], "alice");
+
+        // Later chunks carry no header
+        let chunk = export_transactions(ExportRequest {
+            filter: TransactionFilter {
+                start_after: chunk.next_cursor,
+                ..Default::default()
+            },
+            format: ExportFormat::Csv,
+        });
+        assert_eq!(chunk.rows, 1);
+        assert!(chunk.data.starts_with("3,"));
+        assert_eq!(chunk.next_cursor, None);
+
+        let chunk = export_transactions(ExportRequest {
+            filter: TransactionFilter::default(),
+            format: ExportFormat::Json,
+        });
+        let rows: serde_json::Value = serde_json::from_str(&chunk.data).unwrap();
+        assert_eq!(rows.as_array().unwrap().len(), 3);
+        assert_eq!(rows[2]["index"], 3);
+        assert_eq!(rows[2]["to_icrc1"], alice_icrc1.as_str());
+        assert_eq!(rows[2]["subaccount_index"], 0);
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn format_icrc1_account_round_trips() {
+        let account = parse_icrc1_account(ICRC1_TEXT).unwrap();
+        assert_eq!(format_icrc1_account(&account).unwrap(), ICRC1_TEXT);
+
+        let default_account = Icrc1Account {
+            owner: *STATIC_PRINCIPAL,
+            subaccount: Some(vec![0u8; 32]),
+        };
+        assert_eq!(
+            format_icrc1_account(&default_account).unwrap(),
+            STATIC_PRINCIPAL.to_text()
+        );
+    }
+
+    #[test]
+    fn aggregates_follow_deposits_and_sweep_status() {
+        setup_subaccounts();
+        PRINCIPAL.with(|p| {
+            let _ = p.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let bob = from_hex(&get_subaccountid(2).unwrap()).unwrap().to_vec();
+        let mut outbound = deposit_block(vec![9u8; 32], 900, None);
+        if let Some(Operation::Transfer(data)) = &mut outbound.transaction.operation {
+            data.from = alice.clone();
+        }
+        // Refunds go back to the spender
+        let mut refundable = deposit_block(alice.clone(), 500, None);
+        if let Some(Operation::Transfer(data)) = &mut refundable.transaction.operation {
+            data.spender = Some(vec![2u8; 29]);
+        }
+        let blocks = vec![
+            refundable,
+            deposit_block(alice.clone(), 300, None),
+            deposit_block(bob.clone(), 700, None),
+            outbound,
+        ];
+        store_blocks(1, &blocks);
+
+        let alice_summary = || get_account_summary(AccountQuery::Nonce(0)).unwrap();
+        let summary = alice_summary();
+        assert_eq!(summary.deposit_count, 2);
+        assert_eq!(summary.total_received_e8s, 2000);
+        assert_eq!(summary.pending_e8s, 2000);
+        assert_eq!(summary.first_deposit_at, Some(Timestamp::from_nanos(300)));
+        assert_eq!(summary.last_deposit_at, Some(Timestamp::from_nanos(500)));
+        assert_eq!(
+            get_account_summary(AccountQuery::Nonce(1)).unwrap(),
+            AccountSummary::default()
+        );
+
+        sweep_user_vault().unwrap();
+        let summary = alice_summary();
+        assert_eq!(summary.total_swept_e8s, 2000);
+        assert_eq!(summary.pending_e8s, 0);
+
+        // A failed sweep puts the amount back to pending, and a refund settles it again
+        icrc1_transfer_error_handling(Icrc1TransferRequest::new(
+            ToRecord::new(*STATIC_PRINCIPAL, None),
+            None,
+            Some(1u64.to_be_bytes().to_vec()),
+            None,
+            None,
+            1000,
+        ));
+        let summary = alice_summary();
+        assert_eq!(summary.total_swept_e8s, 1000);
+        assert_eq!(summary.pending_e8s, 1000);
+        refund(1, None).unwrap();
+        let summary = alice_summary();
+        assert_eq!(summary.total_refunded_e8s, 1000);
+        assert_eq!(summary.pending_e8s, 0);
+
+        let totals = get_totals();
+        assert_eq!(totals.deposit_count, 3);
+        assert_eq!(totals.total_received_e8s, 3000);
+        assert_eq!(totals.total_swept_e8s, 2000);
+        assert_eq!(totals.total_refunded_e8s, 1000);
+        assert_eq!(totals.first_deposit_at, Some(Timestamp::from_nanos(300)));
+        assert_eq!(totals.last_deposit_at, Some(Timestamp::from_nanos(700)));
+
+        // Stored history is aggregated after an upgrade from before the aggregates existed,
+        // counting a sweep made while the pass is under way once
+        ACCOUNT_SUMMARIES.with(|s| s.borrow_mut().clear_new());
+        TOTALS.with(|t| {
+            let _ = t.borrow_mut().set(AccountSummary::default());
+        });
+        BACKFILL_STATE.with(|s| {
+            let _ = s.borrow_mut().set(BackfillState::default());
+        });
+        start_backfill();
+        icrc1_transfer_error_handling(Icrc1TransferRequest::new(
+            ToRecord::new(*STATIC_PRINCIPAL, None),
+            None,
+            Some(1u64.to_be_bytes().to_vec()),
+            None,
+            None,
+            1000,
+        ));
+        refund(1, None).unwrap();
+        backfill_batch();
+        assert_eq!(get_totals(), totals);
+
+        // Lifetime figures survive clearing history, and later upgrades leave them alone
+        clear_transactions(None, None, None, Some(true)).unwrap();
+        start_backfill();
+        assert_eq!(get_totals(), totals);
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn invoices_accumulate_deposits_by_address_and_memo() {
+        setup_subaccounts();
+
+        let dedicated = create_invoice(1500, None, None, Some("order-1".to_string())).unwrap();
+        let address = from_hex(dedicated.deposit_address.as_ref().unwrap())
+            .unwrap()
+            .to_vec();
+        let by_memo = create_invoice(1000, None, Some(42), None).unwrap();
+        assert_eq!(by_memo.deposit_address, None);
+        assert!(create_invoice(1000, None, Some(42), None).is_err());
+        assert!(create_invoice(1000, None, Some(0), None).is_err());
+        assert!(create_invoice(0, None, None, None).is_err());
+
+        // The memo invoice is paid to one of the regular deposit addresses
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let mut memo_payment = deposit_block(alice, 500, None);
+        memo_payment.transaction.memo = 42;
+        store_blocks(1, &[deposit_block(address.clone(), 100, None)]);
+        assert_eq!(
+            get_invoice(dedicated.id).unwrap().state,
+            InvoiceState::Underpaid
+        );
+
+        store_blocks(2, &[deposit_block(address, 200, None), memo_payment]);
+        let paid = get_invoice(dedicated.id).unwrap();
+        assert_eq!(paid.state, InvoiceState::Overpaid);
+        assert_eq!(paid.received_e8s, 2000);
+        assert_eq!(paid.payments, vec![1, 2]);
+        assert_eq!(paid.payment_count, 2);
+        assert_eq!(get_invoice(by_memo.id).unwrap().state, InvoiceState::Paid);
+
+        let pending = create_invoice(1000, None, Some(7), None).unwrap();
+        let page = list_invoices(ListInvoicesRequest {
+            state: Some(InvoiceState::Pending),
+            ..Default::default()
+        });
+        assert_eq!(page.invoices, vec![pending]);
+        assert!(get_invoice(99).is_err());
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn invoices_get_their_own_address_under_every_scheme() {
+        CUSTODIAN_PRINCIPAL.with(|cp| {
+            let _ = cp.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+        let schemes = [
+            DerivationScheme::Nonce32,
+            DerivationScheme::Counter64,
+            DerivationScheme::Hashed {
+                namespace: "shop".to_string(),
+            },
+            DerivationScheme::PrincipalEmbedded,
+        ];
+        for scheme in schemes {
+            let _ = DERIVATION_SCHEME.with(|s| s.borrow_mut().set(scheme.clone()));
+            let first = create_invoice(1000, None, None, None).unwrap();
+            let second = create_invoice(1000, None, None, None).unwrap();
+            assert_ne!(
+                first.deposit_address, second.deposit_address,
+                "{:?}",
+                scheme
+            );
+
+            let address = from_hex(second.deposit_address.as_ref().unwrap())
+                .unwrap()
+                .to_vec();
+            let next_block = NEXT_BLOCK.with(|n| *n.borrow().get()).max(1);
+            let next_block = store_blocks(next_block, &[deposit_block(address, 100, None)]);
+            let _ = NEXT_BLOCK.with(|n| n.borrow_mut().set(next_block));
+            assert_eq!(get_invoice(second.id).unwrap().state, InvoiceState::Paid);
+            assert_eq!(get_invoice(first.id).unwrap().state, InvoiceState::Pending);
+        }
+
+        let _ = DERIVATION_SCHEME.with(|s| s.borrow_mut().set(DerivationScheme::default()));
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn invoice_expires_unless_paid_in_time() {
+        let mut invoice = Invoice {
+            id: 1,
+            amount_e8s: 1000,
+            received_e8s: 0,
+            deposit_address: None,
+            memo: Some(1),
+            metadata: None,
+            state: InvoiceState::Pending,
+            created_at: Timestamp::from_nanos(0),
+            expires_at: Some(Timestamp::from_nanos(100)),
+            payments: Vec::new(),
+            payment_count: 0,
+        };
+        assert_eq!(
+            invoice.state_at(&Timestamp::from_nanos(99)),
+            InvoiceState::Pending
+        );
+        assert_eq!(
+            invoice.state_at(&Timestamp::from_nanos(100)),
+            InvoiceState::Expired
+        );
+
+        invoice.apply_payment(1, 400, &Timestamp::from_nanos(50));
+        assert_eq!(invoice.state, InvoiceState::Underpaid);
+        invoice.apply_payment(2, 600, &Timestamp::from_nanos(150));
+        assert_eq!(invoice.state, InvoiceState::Expired);
+        assert_eq!(invoice.received_e8s, 1000);
+
+        // Only the first payments are listed, however many arrive
+        for block_index in 3..100 {
+            invoice.apply_payment(block_index, 1, &Timestamp::from_nanos(150));
+        }
+        assert_eq!(invoice.payments.len(), MAX_INVOICE_PAYMENTS);
+        assert_eq!(invoice.payments[..2], [1, 2]);
+        assert_eq!(invoice.payment_count, 99);
+
+        let mut paid = invoice.clone();
+        paid.state = InvoiceState::Paid;
+        assert_eq!(
+            paid.state_at(&Timestamp::from_nanos(200)),
+            InvoiceState::Paid
+        );
+    }
+
+    #[test]
+    fn memo_mode_attributes_deposits_or_queues_them_for_review() {
+        setup_subaccounts();
+
+        set_memo_attribution(AccountQuery::Nonce(0), true).unwrap();
+        assert!(set_memo_attribution(
+            AccountQuery::Address(hex::encode(vec_to_array(vec![9u8; 32]))),
+            true
+        )
+        .is_err());
+        set_memo_reference(MemoValue::Legacy(42), Some("customer-1".to_string())).unwrap();
+        set_memo_reference(
+            MemoValue::Icrc1(vec![1, 2, 3]),
+            Some("customer-2".to_string()),
+        )
+        .unwrap();
+        assert!(set_memo_reference(MemoValue::Icrc1(vec![0; 33]), None).is_err());
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let bob = from_hex(&get_subaccountid(2).unwrap()).unwrap().to_vec();
+        let with_memo = |to: &Vec<u8>, memo: u64, icrc1_memo: Option<Vec<u8>>| {
+            let mut block = deposit_block(to.clone(), 0, None);
+            block.transaction.memo = memo;
+            block.transaction.icrc1_memo = icrc1_memo;
+            block
+        };
+        let blocks = vec![
+            with_memo(&alice, 42, None),
+            with_memo(&alice, 0, Some(vec![1, 2, 3])),
+            with_memo(&alice, 0, None),
+            with_memo(&alice, 99, None),
+            with_memo(&bob, 99, None),
+        ];
+        store_blocks(1, &blocks);
+
+        assert_eq!(get_deposit_reference(1), Some("customer-1".to_string()));
+        assert_eq!(get_deposit_reference(2), Some("customer-2".to_string()));
+        assert_eq!(get_deposit_reference(5), None);
+
+        let page = list_review_queue(None, Some(1));
+        assert_eq!(page.transactions[0].index, 3);
+        assert_eq!(page.next_cursor, Some(3));
+        let page = list_review_queue(page.next_cursor, Some(1));
+        assert_eq!(page.transactions[0].index, 4);
+        assert_eq!(page.next_cursor, None);
+
+        // Queued deposits outlive retention and unforced clears even once swept
+        set_sweep_status(1..=5, SweepStatus::Swept);
+        set_retention_policy(RetentionPolicy {
+            max_age_seconds: Some(60),
+            ..Default::default()
+        });
+        prune_transactions(now_nanos());
+        set_retention_policy(RetentionPolicy::default());
+        let remaining: Vec<u64> =
+            TRANSACTIONS.with(|t| t.borrow().iter().map(|(k, _)| k).collect());
+        assert_eq!(remaining, vec![3, 4]);
+        let cleared = clear_transactions(Some(4), None, None, None).unwrap();
+        assert_eq!(cleared.kept_unsettled, 2);
+
+        resolve_review(4, Some("customer-3".to_string())).unwrap();
+        resolve_review(3, None).unwrap();
+        assert!(resolve_review(3, None).is_err());
+        assert_eq!(get_deposit_reference(4), Some("customer-3".to_string()));
+        assert!(list_review_queue(None, None).transactions.is_empty());
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn event_log_records_changes_in_sequence() {
+        setup_subaccounts();
+        PRINCIPAL.with(|p| {
+            let _ = p.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let mut refundable = deposit_block(alice.clone(), 0, None);
+        if let Some(Operation::Transfer(data)) = &mut refundable.transaction.operation {
+            data.spender = Some(vec![2u8; 29]);
+        }
+        store_blocks(1, &[deposit_block(alice.clone(), 0, None), refundable]);
+        sweep_user_vault().unwrap();
+
+        let transfer = |index: u64| {
+            Icrc1TransferRequest::new(
+                ToRecord::new(*STATIC_PRINCIPAL, None),
+                None,
+                Some(index.to_be_bytes().to_vec()),
+                None,
+                None,
+                1000,
+            )
+        };
+        icrc1_transfer_succeeded(transfer(1), 77);
+        icrc1_transfer_error_handling(transfer(2));
+        refund(2, None).unwrap();
+        icrc1_transfer_error_handling(transfer(2));
+
+        let kinds = |page: EventsPage| {
+            page.events
+                .into_iter()
+                .map(|event| event.kind)
+                .collect::<Vec<_>>()
+        };
+        let page = get_events(None, Some(3));
+        assert_eq!(page.latest_seq, 11);
+        assert_eq!(page.events[0].seq, 1);
+        assert!(kinds(page)
+            .iter()
+            .all(|kind| matches!(kind, EventKind::SubaccountCreated { .. })));
+
+        // Resuming after the last processed event returns exactly the rest
+        let page = get_events(Some(3), None);
+        assert_eq!(page.events[0].seq, 4);
+        assert_eq!(
+            kinds(page)[2..],
+            [
+                EventKind::SweepPending { block_index: 1 },
+                EventKind::SweepPending { block_index: 2 },
+                EventKind::SweepSucceeded {
+                    block_index: 1,
+                    ledger_block: 77
+                },
+                EventKind::SweepFailed { block_index: 2 },
+                EventKind::RefundPending { block_index: 2 },
+                EventKind::RefundFailed { block_index: 2 },
+            ]
+        );
+        assert!(get_events(Some(11), None).events.is_empty());
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn subscribers_get_filtered_events_until_acknowledged() {
+        setup_subaccounts();
+
+        // caller() is anonymous in tests, so the anonymous principal can acknowledge
+        let subscriber = Principal::anonymous();
+        let filter = SubscriptionFilter {
+            event_types: Some(vec![EventType::DepositDetected]),
+            min_amount_e8s: Some(500),
+            ..Default::default()
+        };
+        subscribe(subscriber, "on_events".to_string(), filter, Some(0)).unwrap();
+        assert!(subscribe(
+            subscriber,
+            "on_events".to_string(),
+            Default::default(),
+            Some(99)
+        )
+        .is_err());
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let mut small = deposit_block(alice.clone(), 0, None);
+        if let Some(Operation::Transfer(data)) = &mut small.transaction.operation {
+            data.amount = E8s { e8s: 100 };
+        }
+        store_blocks(1, &[deposit_block(alice.clone(), 0, None), small]);
+
+        deliver_events();
+        deliver_events();
+        let notified = NOTIFIED.with(|notified| notified.borrow().clone());
+        assert_eq!(notified, vec![(subscriber, vec![4])]);
+
+        assert!(ack_events(5).is_err());
+        ack_events(4).unwrap();
+        deliver_events();
+        let state = list_subscribers().pop().unwrap();
+        assert_eq!((state.acked_seq, state.sent_seq), (5, 5));
+
+        // An unacknowledged batch is sent again once the timeout has passed
+        store_blocks(3, &[deposit_block(alice, 0, None)]);
+        deliver_events();
+        SUBSCRIBERS.with(|s| {
+            let mut state = s.borrow().get(&subscriber).unwrap();
+            state.last_sent_at = Some(Timestamp::from_nanos(0));
+            s.borrow_mut().insert(subscriber, state);
+        });
+        deliver_events();
+        let notified = NOTIFIED.with(|notified| notified.borrow().clone());
+        assert_eq!(
+            notified[1..],
+            [(subscriber, vec![6]), (subscriber, vec![6])]
+        );
+        assert_eq!(list_subscribers()[0].consecutive_failures, 1);
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn failing_subscriber_is_paused_until_resumed() {
+        let failing = *STATIC_PRINCIPAL;
+        subscribe(failing, "unreachable".to_string(), Default::default(), None).unwrap();
+        append_event(EventKind::SweepPending { block_index: 1 });
+
+        for _ in 0..MAX_DELIVERY_FAILURES {
+            deliver_events();
+        }
+        let state = list_subscribers().pop().unwrap();
+        assert_eq!(state.status, SubscriberStatus::Paused);
+        assert_eq!(state.consecutive_failures, MAX_DELIVERY_FAILURES);
+
+        let resumed = set_subscriber_status(failing, SubscriberStatus::Active).unwrap();
+        assert_eq!(resumed.consecutive_failures, 0);
+        assert_eq!(resumed.acked_seq, 0);
+        unsubscribe(failing).unwrap();
+        assert!(list_subscribers().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_are_limited_to_the_subscriber_or_a_controller() {
+        let subscribe_as = |canister: Principal| {
+            subscribe(canister, "on_events".to_string(), Default::default(), None)
+        };
+        assert!(subscribe_as(own_principal()).is_err());
+
+        // Any other caller may only manage its own subscription
+        let other = Principal::from_slice(&[9; 10]);
+        call_as(*STATIC_PRINCIPAL);
+        assert!(subscribe_as(other).is_err());
+        subscribe_as(*STATIC_PRINCIPAL).unwrap();
+        call_as(other);
+        assert!(set_subscriber_status(*STATIC_PRINCIPAL, SubscriberStatus::Paused).is_err());
+        assert!(unsubscribe(*STATIC_PRINCIPAL).is_err());
+
+        call_as(Principal::anonymous());
+        for n in 1..MAX_SUBSCRIBERS {
+            subscribe_as(Principal::from_slice(&[n as u8; 10])).unwrap();
+        }
+        assert!(subscribe_as(Principal::from_slice(&[0xfe; 10])).is_err());
+        // Replacing an existing subscription does not need a free slot
+        subscribe_as(*STATIC_PRINCIPAL).unwrap();
+        assert_eq!(list_subscribers().len() as u64, MAX_SUBSCRIBERS);
+    }
+
+    #[test]
+    fn webhook_signature_and_backoff() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            hex::encode(webhooks::hmac_sha256(
+                b"Jefe",
+                b"what do ya want for nothing?"
+            )),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Keys longer than a block are hashed first (test case 6)
+        assert_eq!(
+            hex::encode(webhooks::hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+
+        let secs = |failures| webhooks::backoff(failures).as_secs();
+        assert_eq!((secs(1), secs(2), secs(3)), (30, 60, 120));
+        assert_eq!(secs(10), 3600);
+        assert_eq!(secs(u32::MAX), 3600);
+    }
+
+    fn call_as(principal: Principal) {
+        CALLER.with(|caller_ref| *caller_ref.borrow_mut() = principal);
+    }
+
+    #[test]
+    fn only_controllers_manage_a_bounded_number_of_webhooks() {
+        let secret = b"0123456789abcdef".to_vec();
+        let url = |n: u64| format!("https://example.com/{}", n);
+        let webhook = add_webhook(url(0), Default::default(), secret.clone()).unwrap();
+
+        call_as(*STATIC_PRINCIPAL);
+        assert!(add_webhook(url(1), Default::default(), secret.clone()).is_err());
+        assert!(set_webhook_status(webhook.id, SubscriberStatus::Paused).is_err());
+        assert!(remove_webhook(webhook.id).is_err());
+        assert_eq!(list_webhooks().len(), 1);
+
+        call_as(Principal::anonymous());
+        for n in 1..MAX_WEBHOOKS {
+            add_webhook(url(n), Default::default(), secret.clone()).unwrap();
+        }
+        assert!(add_webhook(url(MAX_WEBHOOKS), Default::default(), secret.clone()).is_err());
+        remove_webhook(webhook.id).unwrap();
+        assert!(add_webhook(url(MAX_WEBHOOKS), Default::default(), secret).is_ok());
+    }
+
+    #[test]
+    fn webhooks_post_signed_batches_and_back_off() {
+        let secret = b"0123456789abcdef".to_vec();
+        assert!(add_webhook(
+            "http://example.com".to_string(),
+            Default::default(),
+            secret.clone()
+        )
+        .is_err());
+        assert!(add_webhook(
+            "https://example.com".to_string(),
+            Default::default(),
+            vec![1; 8]
+        )
+        .is_err());
+
+        append_event(EventKind::SweepPending { block_index: 1 });
+        let webhook = add_webhook(
+            "https://example.com/hook".to_string(),
+            Default::default(),
+            secret.clone(),
+        )
+        .unwrap();
+        assert_eq!(webhook.delivered_seq, 1);
+        append_event(EventKind::SweepPending { block_index: 2 });
+        append_event(EventKind::SweepFailed { block_index: 2 });
+
+        let now = now_nanos();
+        let spawned = || SPAWNED.with(|spawned| *spawned.borrow());
+        assert_eq!(deliver_webhooks(now), None);
+        assert_eq!(spawned(), 1);
+        assert!(WEBHOOKS_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&webhook.id)));
+        // Nothing new is started while the outcall is under way
+        assert_eq!(deliver_webhooks(now), None);
+        assert_eq!(spawned(), 1);
+        assert!(WEBHOOK_REQUESTS.with(|requests| requests.borrow().is_empty()));
+        let (headers, body) = webhook_request(webhook.id, &get_events(Some(1), None).events);
+        run_to_completion(post_webhook(
+            webhook.id,
+            webhook.url.clone(),
+            headers,
+            body,
+            2,
+            3,
+        ));
+        let delivered = list_webhooks().pop().unwrap();
+        assert_eq!(delivered.delivered_seq, 3);
+        assert_eq!(delivered.last_delivery.unwrap().http_status, Some(200));
+
+        // The request is signed over its exact body
+        let requests = WEBHOOK_REQUESTS.with(|requests| requests.borrow().clone());
+        let (url, headers, body) = requests.last().unwrap();
+        assert_eq!(url, "https://example.com/hook");
+        assert!(String::from_utf8(body.clone())
+            .unwrap()
+            .starts_with("{\"webhook_id\":1,\"events\":[{\"seq\":2"));
+        let signature = headers
+            .iter()
+            .find(|header| header.name == webhooks::SIGNATURE_HEADER)
+            .unwrap();
+        assert_eq!(signature.value, webhooks::signature(&secret, body));
+
+        // Failures back off, and enough of them pause the webhook
+        append_event(EventKind::SweepPending { block_index: 3 });
+        WEBHOOK_RESPONSE_STATUS.with(|status| status.replace(500));
+        run_to_completion(post_webhook(
+            webhook.id,
+            webhook.url.clone(),
+            vec![],
+            vec![],
+            4,
+            4,
+        ));
+        let failed = list_webhooks().pop().unwrap();
+        assert_eq!(failed.delivered_seq, 3);
+        assert_eq!(failed.consecutive_failures, 1);
+        let due = failed.next_attempt_at.unwrap().timestamp_nanos;
+        assert_eq!(deliver_webhooks(now_nanos()), Some(due));
+
+        for _ in 1..MAX_WEBHOOK_FAILURES {
+            record_webhook_result(webhook.id, 4, 4, Some(500), Some("failed".to_string()));
+        }
+        assert_eq!(list_webhooks()[0].status, SubscriberStatus::Paused);
+        let resumed = set_webhook_status(webhook.id, SubscriberStatus::Active).unwrap();
+        assert_eq!(resumed.next_attempt_at, None);
+        remove_webhook(webhook.id).unwrap();
+        assert!(remove_webhook(webhook.id).is_err());
+    }
+
+    fn http_get(url: &str) -> (u16, serde_json::Value) {
+        let response = http_request(http::HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: vec![],
+        });
+        (
+            response.status_code,
+            serde_json::from_slice(&response.body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn http_gateway_serves_filtered_pages_as_json() {
+        setup_subaccounts();
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let bob = from_hex(&get_subaccountid(2).unwrap()).unwrap().to_vec();
+        let blocks = vec![
+            deposit_block(alice.clone(), 100, None),
+            deposit_block(bob.clone(), 200, None),
+            deposit_block(alice.clone(), 300, None),
+        ];
+        store_blocks(1, &blocks);
+
+        let (status, body) = http_get("/transactions?limit=1&direction=Descending");
+        assert_eq!(status, 200);
+        assert_eq!(body["transactions"][0]["index"], 3);
+        assert_eq!(body["next_cursor"], 3);
+        let (_, body) = http_get("/transactions?limit=1&direction=Descending&start_after=3");
+        assert_eq!(body["transactions"][0]["index"], 2);
+
+        let (_, body) = http_get("/transactions?from_timestamp=150&sweep_status=NotSwept");
+        let indices: Vec<_> = body["transactions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["index"].as_u64().unwrap())
+            .collect();
+        assert_eq!(indices, vec![2, 3]);
+
+        let (_, body) = http_get("/accounts/0/transactions?direction=Descending");
+        assert_eq!(body["transactions"][0]["index"], 3);
+        assert_eq!(body["transactions"][1]["index"], 1);
+        let (status, body) = http_get(&format!("/accounts/{}/summary", hex::encode(&alice)));
+        assert_eq!(status, 200);
+        assert_eq!(body["deposit_count"], 2);
+        assert_eq!(body["total_received_e8s"], 2000);
+
+        let (_, body) = http_get("/metrics");
+        assert_eq!(body["transaction_count"], 3);
+        assert_eq!(body["totals"]["deposit_count"], 3);
+        let (status, _) = http_get("/status");
+        assert_eq!(status, 200);
+
+        let (status, body) = http_get("/transactions?direction=Sideways");
+        assert_eq!(status, 400);
+        assert_eq!(body["error"], "Invalid value for direction");
+        let (status, _) = http_get("/accounts/not-an-account/summary");
+        assert_eq!(status, 400);
+        let (status, _) = http_get("/nowhere");
+        assert_eq!(status, 404);
+        let response = http_request(http::HttpRequest {
+            method: "POST".to_string(),
+            url: "/status".to_string(),
+            headers: vec![],
+            body: vec![],
+        });
+        assert_eq!(response.status_code, 405);
+    }
+
+    // The routes are only served through the raw domain, since nothing certifies them
+    #[test]
+    fn http_responses_carry_no_certificate() {
+        for url in ["/status", "/transactions", "/nowhere"] {
+            let response = http_request(http::HttpRequest {
+                method: "GET".to_string(),
+                url: url.to_string(),
+                headers: vec![("Host".to_string(), "example.raw.icp0.io".to_string())],
+                body: vec![],
+            });
+            assert!(response
+                .headers
+                .iter()
+                .all(|(name, _)| !name.eq_ignore_ascii_case("IC-Certificate")));
+        }
+    }
+
+    fn decode_witness(witness: &[u8]) -> ic_certification::HashTree {
+        serde_cbor::from_slice(witness).unwrap()
+    }
+
+    #[test]
+    fn certified_witnesses_prove_stored_transactions() {
+        setup_subaccounts();
+        PRINCIPAL.with(|p| {
+            let _ = p.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let blocks = vec![
+            deposit_block(alice.clone(), 100, None),
+            deposit_block(vec![9u8; 32], 200, None),
+            deposit_block(alice.clone(), 300, None),
+        ];
+        let next_block = store_blocks(1, &blocks);
+        run_to_completion(set_next_block(next_block));
+
+        let response = get_certified_transactions(ListTransactionsRequest::default());
+        assert_eq!(response.page.transactions.len(), 2);
+        assert!(response.certificate.is_none());
+        let witness = decode_witness(&response.witness);
+        assert_eq!(witness.digest(), certified_root());
+        assert_eq!(
+            witness.lookup_path([b"next_block".as_slice()]),
+            ic_certification::LookupResult::Found(&4u64.to_be_bytes())
+        );
+        for transaction in &response.page.transactions {
+            let hash = certification::transaction_hash(transaction);
+            assert_eq!(
+                witness.lookup_path([b"transactions".as_slice(), &transaction.index.to_be_bytes()]),
+                ic_certification::LookupResult::Found(&hash)
+            );
+        }
+        // The range proof also shows that nothing lies between the returned entries
+        assert_eq!(
+            witness.lookup_path([b"transactions".as_slice(), &2u64.to_be_bytes()]),
+            ic_certification::LookupResult::Absent
+        );
+
+        // Changes to a transaction move the certified root
+        let before = certified_root();
+        sweep_user_vault().unwrap();
+        assert_ne!(certified_root(), before);
+        let response = get_certified_transaction(3);
+        let swept = response.transaction.unwrap();
+        assert_eq!(swept.sweep_status, SweepStatus::Swept);
+        let witness = decode_witness(&response.witness);
+        assert_eq!(witness.digest(), certified_root());
+        assert_eq!(
+            witness.lookup_path([b"transactions".as_slice(), &3u64.to_be_bytes()]),
+            ic_certification::LookupResult::Found(&certification::transaction_hash(&swept))
+        );
+        // The leaf is specified byte by byte, not by our Candid encoding
+        let mut leaf = swept.block_hash.clone().unwrap();
+        leaf.extend([1, 0]);
+        assert_eq!(
+            certification::transaction_hash(&swept).to_vec(),
+            <sha2::Sha256 as sha2::Digest>::digest(&leaf).to_vec()
+        );
+
+        let response = get_certified_transaction(2);
+        assert!(response.transaction.is_none());
+        let witness = decode_witness(&response.witness);
+        assert_eq!(witness.digest(), certified_root());
+        assert_eq!(
+            witness.lookup_path([b"transactions".as_slice(), &2u64.to_be_bytes()]),
+            ic_certification::LookupResult::Absent
+        );
+
+        // After an upgrade nothing is certified until the tree has been rebuilt in batches
+        let root = certified_root();
+        let certified = || CERTIFIED_DATA.with(|data| data.borrow().clone());
+        assert_eq!(certified(), root.to_vec());
+        CERTIFIED_TRANSACTIONS.with(|tree| *tree.borrow_mut() = Default::default());
+        let extra: Vec<Block> = (0..600)
+            .map(|i| deposit_block(alice.clone(), 1000 + i, None))
+            .collect();
+        TRANSACTIONS.with(|t| {
+            for (offset, block) in extra.iter().enumerate() {
+                let index = 10 + offset as u64;
+                t.borrow_mut()
+                    .insert(index, StoredTransactions::from_block(index, block.clone()));
+            }
+        });
+        rebuild_certified_tree();
+        assert!(certified().is_empty());
+        certify_batch();
+        assert!(certified().is_empty());
+        sweep_user_vault().unwrap();
+        assert!(certified().is_empty());
+        certify_batch();
+        assert_eq!(certified(), certified_root().to_vec());
+        assert_eq!(
+            CERTIFIED_TRANSACTIONS.with(|tree| tree.borrow().iter().count()),
+            602
+        );
+
+        teardown_subaccounts();
+    }
+
+    fn blocks_response(first_block_index: u64, blocks: Vec<Block>) -> QueryBlocksResponse {
+        QueryBlocksResponse {
+            certificate: None,
+            blocks,
+            chain_length: 100,
+            first_block_index,
+            archived_blocks: vec![],
+        }
+    }
+
+    #[test]
+    fn ingestion_rejects_blocks_that_break_the_hash_chain() {
+        setup_subaccounts();
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let first = deposit_block(alice.clone(), 100, Some(vec![1u8; 32]));
+        let second = deposit_block(
+            alice.clone(),
+            200,
+            Some(chain::block_hashes(&first)[0].clone()),
+        );
+        let hashes = chain::block_hashes(&second);
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(
+            ingest_blocks(1, &blocks_response(1, vec![first, second])),
+            Ok(3)
+        );
+        assert_eq!(
+            get_chain_tip(),
+            Some(ChainTip {
+                index: 2,
+                hashes: hashes.clone()
+            })
+        );
+
+        // A block that does not name its predecessor is not stored and sync does not advance
+        let forged = deposit_block(alice.clone(), 300, Some(vec![2u8; 32]));
+        assert!(ingest_blocks(3, &blocks_response(3, vec![forged])).is_err());
+        assert!(get_certified_transaction(3).transaction.is_none());
+        assert_eq!(get_chain_tip().unwrap().index, 2);
+        let skipped = deposit_block(alice.clone(), 300, Some(hashes[0].clone()));
+        assert!(ingest_blocks(3, &blocks_response(5, vec![skipped])).is_err());
+
+        // Without created_at_time the ledger reports the block timestamp, so either form links
+        let mut third = deposit_block(alice.clone(), 300, Some(hashes[0].clone()));
+        third.transaction.created_at_time = Timestamp::from_nanos(300);
+        let third_hashes = chain::block_hashes(&third);
+        assert_eq!(third_hashes.len(), 2);
+        let fourth = deposit_block(alice.clone(), 400, Some(third_hashes[1].clone()));
+        assert_eq!(
+            ingest_blocks(3, &blocks_response(3, vec![third, fourth])),
+            Ok(5)
+        );
+        assert_eq!(get_chain_tip().unwrap().index, 4);
+        assert!(get_certified_transaction(4).transaction.is_some());
+    }
+
+    #[test]
+    fn reset_chain_tip_lets_a_controller_restart_the_chain() {
+        setup_subaccounts();
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let first = deposit_block(alice.clone(), 100, None);
+        assert_eq!(ingest_blocks(1, &blocks_response(1, vec![first])), Ok(2));
+        let previous = get_chain_tip().unwrap();
+        let unlinked = || deposit_block(alice.clone(), 200, Some(vec![2u8; 32]));
+        assert!(ingest_blocks(2, &blocks_response(2, vec![unlinked()])).is_err());
+
+        call_as(*STATIC_PRINCIPAL);
+        assert!(reset_chain_tip().is_err());
+        assert_eq!(get_chain_tip(), Some(previous.clone()));
+        call_as(Principal::anonymous());
+
+        // Without a tip the next batch starts a new chain, but its own links are still checked
+        assert_eq!(reset_chain_tip().unwrap(), Some(previous.clone()));
+        assert_eq!(get_chain_tip(), None);
+        assert!(ingest_blocks(2, &blocks_response(2, vec![unlinked(), unlinked()])).is_err());
+        assert_eq!(
+            ingest_blocks(2, &blocks_response(2, vec![unlinked()])),
+            Ok(3)
+        );
+        assert_eq!(get_chain_tip().unwrap().index, 2);
+        assert!(ingest_blocks(3, &blocks_response(3, vec![unlinked()])).is_err());
+
+        let records = get_audit_log(None, None).records;
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].event,
+            AuditEvent::ChainTipReset {
+                caller: Principal::anonymous(),
+                previous: Some(previous),
+            }
+        );
+
+        teardown_subaccounts();
+    }
+
+    #[test]
+    fn canister_status_reports_sync_memory_and_pending_transfers() {
+        setup_subaccounts();
+        PRINCIPAL.with(|p| {
+            let _ = p.borrow_mut().set(StoredPrincipal::new(*STATIC_PRINCIPAL));
+        });
+
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let mut refundable = deposit_block(alice.clone(), 100, None);
+        if let Some(Operation::Transfer(data)) = &mut refundable.transaction.operation {
+            data.spender = Some(vec![2u8; 29]);
+        }
+        store_blocks(1, &[refundable, deposit_block(alice.clone(), 200, None)]);
+
+        run_to_completion(call_query_blocks());
+        let status = canister_status();
+        assert!(status.sync.last_success_at.is_some());
+        assert_eq!(status.sync.chain_length, Some(0));
+        assert_eq!(status.stable_memory.len(), 29);
+        let transactions = &status.stable_memory[4];
+        assert_eq!(transactions.name, "TRANSACTIONS");
+        assert!(transactions.size_bytes > 0);
+
+        record_sync(Err("query_blocks failed".to_string()));
+        record_sync(Err("query_blocks failed".to_string()));
+        let status = canister_status();
+        assert_eq!(status.sync.consecutive_errors, 2);
+        assert!(status.sync.last_failure_at.is_some());
+        assert_eq!(
+            status.sync.last_error.as_deref(),
+            Some("query_blocks failed")
+        );
+
+        run_to_completion(set_next_block(3));
+        record_sync(Ok(10));
+        let status = canister_status();
+        assert_eq!(status.sync.consecutive_errors, 0);
+        assert_eq!(status.sync_lag, Some(7));
+
+        sweep_user_vault().unwrap();
+        assert_eq!(canister_status().pending_sweeps, 2);
+        icrc1_transfer_error_handling(Icrc1TransferRequest::new(
+            ToRecord::new(*STATIC_PRINCIPAL, None),
+            None,
+            Some(1u64.to_be_bytes().to_vec()),
+            None,
+            None,
+            1000,
+        ));
+        refund(1, None).unwrap();
+        let status = canister_status();
+        assert_eq!(status.pending_sweeps, 1);
+        assert_eq!(status.pending_refunds, 1);
+    }
+
+    #[test]
+    fn backfill_indexes_stored_history_in_batches() {
+        setup_subaccounts();
+        let alice = from_hex(&get_subaccountid(0).unwrap()).unwrap().to_vec();
+        let blocks: Vec<Block> = (0..600)
+            .map(|i| deposit_block(alice.clone(), i, None))
+            .collect();
+        store_blocks(1, &blocks);
+
+        // As if stored before the index existed
+        ACCOUNT_TRANSACTIONS.with(|i| i.borrow_mut().clear_new());
+        BACKFILL_STATE.with(|s| {
+            let _ = s.borrow_mut().set(BackfillState::default());
+        });
+
+        start_backfill();
+        let state = BACKFILL_STATE.with(|s| s.borrow().get().clone());
+        assert!(state.account_index && !state.finished);
+        assert_eq!(state.end, Some(600));
+
+        // Blocks stored during the pass are indexed as they arrive
+        store_blocks(601, &[deposit_block(alice.clone(), 601, None)]);
+        backfill_batch();
+        assert_eq!(BACKFILL_STATE.with(|s| s.borrow().get().cursor), Some(500));
+        backfill_batch();
+        assert!(BACKFILL_STATE.with(|s| s.borrow().get().finished));
+        assert_eq!(ACCOUNT_TRANSACTIONS.with(|i| i.borrow().len()), 601);
+
+        // Later upgrades find nothing to do
+        start_backfill();
+        assert!(BACKFILL_STATE.with(|s| s.borrow().get().finished));
+
+        teardown_subaccounts();
+    }
+    #[test]
+    fn subaccount_ids_resolve_by_nat32_and_nat64_index() {
+        setup_subaccounts();
+
+        for nonce in 0..3u32 {
+            assert_eq!(
+                get_subaccountid_by_index(nonce as u64).unwrap(),
+                get_subaccountid(nonce).unwrap()
+            );
+        }
+        assert!(get_subaccountid(3).is_err());
+        assert!(get_subaccountid_by_index(u32::MAX as u64 + 1).is_err());
+
+        teardown_subaccounts();
+    }
+    #[test]
+    fn audit_log_drops_the_oldest_records_past_its_cap() {
+        for _ in 0..MAX_AUDIT_RECORDS + 2 {
+            append_audit(AuditEvent::RetentionRun {
+                pruned: 0,
+                kept_unsettled: 0,
+            });
+        }
+        assert_eq!(AUDIT_LOG.with(|l| l.borrow().len()), MAX_AUDIT_RECORDS);
+        assert_eq!(get_audit_log(None, Some(1)).records[0].id, 2);
+    }
 }
@@ -1,10 +1,13 @@
 use candid::{CandidType, Deserialize, Principal};
 use core::future::Future;
 use ic_cdk::api::call::CallResult;
+use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse};
 use ic_cdk_timers::TimerId;
 use serde::Serialize;
 use std::{borrow::Cow, collections::HashMap};
 
+use crate::encoding::{decode_transaction, encode_transaction};
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct QueryBlocksRequest {
     pub start: u64,
@@ -212,6 +215,24 @@ pub enum SweepStatus {
     Swept,
     FailedToSweep,
     NotSwept,
+    Refunded,
+}
+
+// Reason a deposit was set aside for manual review instead of being swept
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum TransactionFlag {
+    SubaccountDisabled,
+    SubaccountArchived,
+    WatchOnly,
+}
+
+// Which side of a transfer our accounts are on
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum TransactionDirection {
+    Inbound,
+    Outbound,
+    // between two of our accounts
+    Internal,
 }
 
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
@@ -222,6 +243,111 @@ pub struct StoredTransactions {
     pub operation: Option<Operation>,
     pub created_at_time: Timestamp,
     pub sweep_status: SweepStatus,
+    pub flag: Option<TransactionFlag>,
+    // Ledger-assigned time of the block, unlike the client-supplied created_at_time
+    pub block_timestamp: Option<Timestamp>,
+    pub parent_hash: Option<Vec<u8>>,
+    // Computed when stored; a block without created_at_time has two candidates and waits
+    // for the next block's parent_hash to name one
+    pub block_hash: Option<Vec<u8>>,
+    pub direction: Option<TransactionDirection>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum SortDirection {
+    Ascending,
+    Descending,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ListTransactionsRequest {
+    pub start_after: Option<u64>,
+    pub limit: Option<u64>,
+    pub direction: Option<SortDirection>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum OperationKind {
+    Approve,
+    Burn,
+    Mint,
+    Transfer,
+}
+
+// All bounds are inclusive; unset fields match everything
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TransactionFilter {
+    pub start_after: Option<u64>,
+    pub limit: Option<u64>,
+    pub direction: Option<SortDirection>,
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+    pub from_timestamp: Option<Timestamp>,
+    pub to_timestamp: Option<Timestamp>,
+    pub operation: Option<OperationKind>,
+    pub sweep_status: Option<SweepStatus>,
+    pub min_amount: Option<u64>,
+    pub max_amount: Option<u64>,
+    pub memo: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct ExportRequest {
+    pub filter: TransactionFilter,
+    pub format: ExportFormat,
+}
+
+// JSON chunks are complete arrays; only the first CSV chunk carries the header row
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct ExportChunk {
+    pub data: String,
+    pub rows: u64,
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum AccountQuery {
+    // hex account identifier or ICRC-1 textual account
+    Address(String),
+    Icrc1(Icrc1Account),
+    // subaccount index, which is the nonce under the default derivation scheme
+    Nonce(u64),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct AccountTransactionsRequest {
+    pub account: AccountQuery,
+    pub start_after: Option<u64>,
+    pub limit: Option<u64>,
+    pub direction: Option<SortDirection>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct TransactionsPage {
+    pub transactions: Vec<StoredTransactions>,
+    pub next_cursor: Option<u64>,
+}
+
+// The certificate is only returned to query calls, not to queries made as updates. The
+// witness is CBOR and digests to the certified data when the response is genuine.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct CertifiedTransactionsPage {
+    pub page: TransactionsPage,
+    pub certificate: Option<Vec<u8>>,
+    pub witness: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct CertifiedTransaction {
+    pub transaction: Option<StoredTransactions>,
+    pub certificate: Option<Vec<u8>>,
+    pub witness: Vec<u8>,
 }
 
 // #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -240,8 +366,40 @@ impl StoredTransactions {
             operation: transaction.operation,
             created_at_time: transaction.created_at_time,
             sweep_status: SweepStatus::NotSwept,
+            flag: None,
+            block_timestamp: None,
+            parent_hash: None,
+            block_hash: None,
+            direction: None,
+        }
+    }
+
+    pub fn from_block(index: u64, block: Block) -> Self {
+        Self {
+            block_timestamp: Some(block.timestamp),
+            parent_hash: block.parent_hash,
+            ..Self::new(index, block.transaction)
         }
     }
+
+    // The ledger timestamp, falling back to created_at_time for entries stored before it was kept
+    pub fn timestamp(&self) -> &Timestamp {
+        self.block_timestamp
+            .as_ref()
+            .unwrap_or(&self.created_at_time)
+    }
+
+    // Entries stored before directions were tracked only ever matched on the receiving side
+    pub fn is_inbound(&self) -> bool {
+        matches!(self.direction, None | Some(TransactionDirection::Inbound))
+    }
+
+    pub fn is_settled(&self) -> bool {
+        matches!(
+            self.sweep_status,
+            SweepStatus::Swept | SweepStatus::Refunded
+        )
+    }
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Default)]
@@ -261,6 +419,107 @@ impl StoredPrincipal {
     }
 }
 
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum SubaccountStatus {
+    Active,
+    Disabled,
+    Archived,
+}
+
+// How subaccounts are derived from their registry entry. Fixed at install time so that
+// upgrades reconstruct the same addresses.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub enum DerivationScheme {
+    // u32 big-endian nonce in the last 4 bytes
+    #[default]
+    Nonce32,
+    // u64 big-endian counter in the last 8 bytes
+    Counter64,
+    // sha256 of the namespace and an external id, deterministic per user
+    Hashed {
+        namespace: String,
+    },
+    // ICRC convention: principal length followed by the principal bytes
+    PrincipalEmbedded,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct StoredSubaccount {
+    pub label: Option<String>,
+    pub status: SubaccountStatus,
+    pub external_id: Option<String>,
+    pub owner: Option<Principal>,
+}
+
+impl StoredSubaccount {
+    pub fn new(label: Option<String>) -> Self {
+        Self {
+            label,
+            status: SubaccountStatus::Active,
+            external_id: None,
+            owner: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CreateSubaccountRequest {
+    pub label: Option<String>,
+    pub external_id: Option<String>,
+    pub owner: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ListSubaccountsRequest {
+    pub start_after: Option<u64>,
+    pub limit: Option<u64>,
+    pub label: Option<String>,
+    pub status: Option<SubaccountStatus>,
+    pub has_unswept: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct SubaccountInfo {
+    pub index: u64,
+    pub subaccountid: String,
+    pub label: Option<String>,
+    pub status: SubaccountStatus,
+    pub deposits: AccountSummary,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct ListSubaccountsResponse {
+    pub subaccounts: Vec<SubaccountInfo>,
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Icrc1Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum AccountInput {
+    AccountId(String),
+    Icrc1(Icrc1Account),
+    // hex account identifier or ICRC-1 textual account
+    Text(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct StoredWatchedAccount {
+    pub label: Option<String>,
+    pub icrc1_account: Option<Icrc1Account>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct WatchedAccount {
+    pub account_id: String,
+    pub label: Option<String>,
+    pub icrc1_account: Option<Icrc1Account>,
+}
+
 use ic_stable_structures::{
     memory_manager::VirtualMemory,
     storable::{Bound, Storable},
@@ -268,7 +527,45 @@ use ic_stable_structures::{
 };
 
 const MAX_VALUE_SIZE: u32 = 500;
+
+// Version tag written in front of every stored transaction. Records from before the envelope
+// existed are bare Candid and start with its magic bytes instead, which no version tag collides with.
+// Version 1 is Candid, version 2 the compact encoding in the encoding module.
+pub const TRANSACTION_SCHEMA_VERSION: u8 = 2;
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+impl StoredTransactions {
+    // 0 for records written before the envelope
+    pub fn schema_version(bytes: &[u8]) -> Option<u8> {
+        if bytes.starts_with(CANDID_MAGIC) {
+            return Some(0);
+        }
+        bytes.first().copied()
+    }
+}
+
+// Unbounded, since the V2 BTreeMap layout allows relaxing the bound on an existing map
 impl Storable for StoredTransactions {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![TRANSACTION_SCHEMA_VERSION];
+        bytes.extend(encode_transaction(self));
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        match Self::schema_version(&bytes) {
+            Some(0) => candid::decode_one(&bytes).expect("Decoding StoredTransactions failed"),
+            Some(1) => candid::decode_one(&bytes[1..]).expect("Decoding StoredTransactions failed"),
+            Some(2) => decode_transaction(&bytes[1..])
+                .unwrap_or_else(|e| panic!("Decoding StoredTransactions failed: {}", e)),
+            other => panic!("Unknown StoredTransactions schema version {:?}", other),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StoredPrincipal {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap()) // Assuming using Candid for serialization
     }
@@ -283,13 +580,354 @@ impl Storable for StoredTransactions {
     };
 }
 
-impl Storable for StoredPrincipal {
+impl Storable for StoredSubaccount {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap()) // Assuming using Candid for serialization
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        candid::decode_one(bytes.as_ref()).unwrap() // Assuming using Candid for deserialization
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Lifetime deposit figures, for one account or the whole canister. Pruning history does not
+// reduce them; pending is what has been received but neither swept nor refunded.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct AccountSummary {
+    pub deposit_count: u64,
+    pub total_received_e8s: u64,
+    pub total_swept_e8s: u64,
+    pub total_refunded_e8s: u64,
+    pub pending_e8s: u64,
+    pub first_deposit_at: Option<Timestamp>,
+    pub last_deposit_at: Option<Timestamp>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Metrics {
+    pub transaction_count: u64,
+    pub subaccount_count: u64,
+    pub watched_account_count: u64,
+    pub latest_event_seq: u64,
+    pub review_queue_length: u64,
+    pub invoice_count: u64,
+    pub subscriber_count: u64,
+    pub webhook_count: u64,
+    pub totals: AccountSummary,
+}
+
+// A memo as the payer sent it. A legacy memo is the same as an ICRC-1 memo of its eight
+// big-endian bytes.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum MemoValue {
+    Legacy(u64),
+    Icrc1(Vec<u8>),
+}
+
+impl MemoValue {
+    pub fn bytes(&self) -> Vec<u8> {
+        match self {
+            MemoValue::Legacy(memo) => memo.to_be_bytes().to_vec(),
+            MemoValue::Icrc1(bytes) => bytes.clone(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum InvoiceState {
+    Pending,
+    Paid,
+    Underpaid,
+    Overpaid,
+    Expired,
+}
+
+// An invoice is paid either to its own deposit address, or to any of our addresses with its
+// memo. Payments arriving after expiry are still recorded so they can be refunded.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Invoice {
+    pub id: u64,
+    pub amount_e8s: u64,
+    pub received_e8s: u64,
+    pub deposit_address: Option<String>,
+    pub memo: Option<u64>,
+    pub metadata: Option<String>,
+    pub state: InvoiceState,
+    pub created_at: Timestamp,
+    pub expires_at: Option<Timestamp>,
+    // block indices of the first deposits counted towards the invoice, up to
+    // MAX_INVOICE_PAYMENTS, so dust sent with a known memo cannot grow the record
+    pub payments: Vec<u64>,
+    pub payment_count: u64,
+}
+
+pub const MAX_INVOICE_PAYMENTS: usize = 20;
+
+impl Invoice {
+    // Expiry is applied on read, so the stored state only changes when a deposit arrives
+    pub fn state_at(&self, now: &Timestamp) -> InvoiceState {
+        match (&self.state, &self.expires_at) {
+            (InvoiceState::Pending | InvoiceState::Underpaid, Some(expires_at))
+                if now.timestamp_nanos >= expires_at.timestamp_nanos =>
+            {
+                InvoiceState::Expired
+            }
+            (state, _) => state.clone(),
+        }
+    }
+
+    pub fn apply_payment(&mut self, block_index: u64, amount_e8s: u64, at: &Timestamp) {
+        let expired = self.state_at(at) == InvoiceState::Expired;
+        self.received_e8s = self.received_e8s.saturating_add(amount_e8s);
+        self.payment_count = self.payment_count.saturating_add(1);
+        if self.payments.len() < MAX_INVOICE_PAYMENTS {
+            self.payments.push(block_index);
+        }
+        self.state = if expired {
+            InvoiceState::Expired
+        } else if self.received_e8s < self.amount_e8s {
+            InvoiceState::Underpaid
+        } else if self.received_e8s == self.amount_e8s {
+            InvoiceState::Paid
+        } else {
+            InvoiceState::Overpaid
+        };
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ListInvoicesRequest {
+    pub start_after: Option<u64>,
+    pub limit: Option<u64>,
+    pub state: Option<InvoiceState>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct InvoicesPage {
+    pub invoices: Vec<Invoice>,
+    pub next_cursor: Option<u64>,
+}
+
+// Unset limits are not enforced. Unsettled deposits and deposits awaiting review are never
+// pruned.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct RetentionPolicy {
+    pub max_age_seconds: Option<u64>,
+    pub max_count: Option<u64>,
+    // Only prune swept or refunded entries, keeping outbound and other non-deposit entries too
+    pub settled_only: bool,
+}
+
+impl RetentionPolicy {
+    pub fn is_active(&self) -> bool {
+        self.max_age_seconds.is_some() || self.max_count.is_some()
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum AuditEvent {
+    // a retention pass over all entries the policy applies to, recorded when it finishes
+    RetentionRun {
+        pruned: u64,
+        kept_unsettled: u64,
+    },
+    // clear_transactions deleted deposits that were neither swept nor refunded
+    ForcedClear {
+        caller: Principal,
+        indices: Vec<u64>,
+        amount_e8s: u64,
+    },
+    // reset_chain_tip dropped the stored tip
+    ChainTipReset {
+        caller: Principal,
+        previous: Option<ChainTip>,
+    },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub id: u64,
+    pub timestamp: Timestamp,
+    pub event: AuditEvent,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ClearTransactionsResponse {
+    pub removed: u64,
+    // unsettled deposits in range that were left in place
+    pub kept_unsettled: u64,
+    pub remaining: u64,
+    // set when the budget ran out; pass it back as start_after to continue
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct AuditLogPage {
+    pub records: Vec<AuditRecord>,
+    pub next_cursor: Option<u64>,
+}
+
+// Block indices refer to the deposit the event concerns; ledger_block is the block of the
+// outgoing transfer once the ledger has accepted it.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum EventKind {
+    DepositDetected {
+        block_index: u64,
+        account: String,
+        amount_e8s: u64,
+    },
+    SweepPending {
+        block_index: u64,
+    },
+    SweepSucceeded {
+        block_index: u64,
+        ledger_block: u64,
+    },
+    SweepFailed {
+        block_index: u64,
+    },
+    RefundPending {
+        block_index: u64,
+    },
+    RefundSucceeded {
+        block_index: u64,
+        ledger_block: u64,
+    },
+    RefundFailed {
+        block_index: u64,
+    },
+    SubaccountCreated {
+        index: u64,
+        account: String,
+    },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum EventType {
+    DepositDetected,
+    SweepPending,
+    SweepSucceeded,
+    SweepFailed,
+    RefundPending,
+    RefundSucceeded,
+    RefundFailed,
+    SubaccountCreated,
+}
+
+impl EventKind {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            EventKind::DepositDetected { .. } => EventType::DepositDetected,
+            EventKind::SweepPending { .. } => EventType::SweepPending,
+            EventKind::SweepSucceeded { .. } => EventType::SweepSucceeded,
+            EventKind::SweepFailed { .. } => EventType::SweepFailed,
+            EventKind::RefundPending { .. } => EventType::RefundPending,
+            EventKind::RefundSucceeded { .. } => EventType::RefundSucceeded,
+            EventKind::RefundFailed { .. } => EventType::RefundFailed,
+            EventKind::SubaccountCreated { .. } => EventType::SubaccountCreated,
+        }
+    }
+
+    // The deposit the event concerns, if any
+    pub fn block_index(&self) -> Option<u64> {
+        match self {
+            EventKind::DepositDetected { block_index, .. }
+            | EventKind::SweepPending { block_index }
+            | EventKind::SweepSucceeded { block_index, .. }
+            | EventKind::SweepFailed { block_index }
+            | EventKind::RefundPending { block_index }
+            | EventKind::RefundSucceeded { block_index, .. }
+            | EventKind::RefundFailed { block_index } => Some(*block_index),
+            EventKind::SubaccountCreated { .. } => None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub seq: u64,
+    pub timestamp: Timestamp,
+    pub kind: EventKind,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct EventsPage {
+    pub events: Vec<Event>,
+    // highest sequence number in the log, so consumers know whether to poll again
+    pub latest_seq: u64,
+}
+
+// Unset criteria match everything. Accounts are hex account identifiers; the minimum amount
+// applies to events about a deposit.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct SubscriptionFilter {
+    pub accounts: Option<Vec<String>>,
+    pub event_types: Option<Vec<EventType>>,
+    pub min_amount_e8s: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum SubscriberStatus {
+    Active,
+    Paused,
+}
+
+// Events up to acked_seq have been confirmed by the subscriber. A batch up to sent_seq is
+// awaiting acknowledgement while sent_seq is ahead, and is sent again if none arrives.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Subscriber {
+    pub canister: Principal,
+    pub method: String,
+    pub filter: SubscriptionFilter,
+    pub acked_seq: u64,
+    pub sent_seq: u64,
+    pub last_sent_at: Option<Timestamp>,
+    pub consecutive_failures: u32,
+    pub status: SubscriberStatus,
+}
+
+// The outcome of the latest attempt to deliver events first_seq..=last_seq
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct WebhookDelivery {
+    pub first_seq: u64,
+    pub last_seq: u64,
+    pub attempted_at: Timestamp,
+    pub http_status: Option<u64>,
+    pub error: Option<String>,
+}
+
+// Events up to delivered_seq were accepted with a 2xx response. The signing secret is kept
+// apart so it is never returned.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    pub filter: SubscriptionFilter,
+    pub delivered_seq: u64,
+    pub status: SubscriberStatus,
+    pub consecutive_failures: u32,
+    pub next_attempt_at: Option<Timestamp>,
+    pub last_delivery: Option<WebhookDelivery>,
+}
+
+// The last block whose link to its predecessor was checked. There is no tip until the first
+// block is verified, or after an admin resets it.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ChainTip {
+    pub index: u64,
+    // usually one hash; two while the block's created_at_time is ambiguous
+    pub hashes: Vec<Vec<u8>>,
+}
+
+impl Storable for ChainTip {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
     }
 
     const BOUND: Bound = Bound::Bounded {
@@ -298,10 +936,228 @@ impl Storable for StoredPrincipal {
     };
 }
 
+// Outcome of the block fetches. The chain length is the ledger's, as of its latest reply.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct SyncState {
+    pub last_success_at: Option<Timestamp>,
+    pub last_failure_at: Option<Timestamp>,
+    pub last_error: Option<String>,
+    pub consecutive_errors: u32,
+    pub chain_length: Option<u64>,
+}
+
+impl Storable for SyncState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct StableMemoryUsage {
+    pub memory_id: u8,
+    pub name: String,
+    pub size_bytes: u64,
+}
+
+// Pending sweeps and refunds are ledger transfers that have not returned yet
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CanisterStatus {
+    pub cycles: u128,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub stable_memory: Vec<StableMemoryUsage>,
+    pub next_block: u64,
+    // blocks the ledger has that we have not fetched yet
+    pub sync_lag: Option<u64>,
+    pub sync: SyncState,
+    pub sync_timer_active: bool,
+    pub interval_seconds: u64,
+    pub pending_sweeps: u64,
+    pub pending_refunds: u64,
+}
+
+// Progress of rewriting stored transactions into the current schema version
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct MigrationState {
+    pub schema_version: u8,
+    // last block rewritten by the migration in progress
+    pub cursor: Option<u64>,
+}
+
+// A one-off pass that builds the account index and deposit aggregates from transactions
+// stored before they existed. Blocks past `end` are counted as they arrive.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct BackfillState {
+    pub finished: bool,
+    pub account_index: bool,
+    pub aggregates: bool,
+    // last block processed by the pass in progress
+    pub cursor: Option<u64>,
+    // newest block stored when the pass started
+    pub end: Option<u64>,
+}
+
+impl Storable for BackfillState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for MigrationState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for RetentionPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for AccountSummary {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Invoice {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Event {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Subscriber {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Webhook {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AuditRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for DerivationScheme {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for StoredWatchedAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 pub trait TimerManagerTrait {
     fn set_timer(interval: std::time::Duration) -> TimerId;
+    fn set_timer_once(delay: std::time::Duration, task: fn()) -> TimerId;
     fn clear_timer(timer_id: TimerId);
 }
 
@@ -316,10 +1172,22 @@ pub trait InterCanisterCallManagerTrait {
         ledger_principal: Principal,
         req: Icrc1TransferRequest,
     ) -> CallResult<(Icrc1TransferResponse,)>;
+    // One-way call; an error only means the call could not be enqueued
+    fn notify_events(canister: Principal, method: &str, events: Vec<Event>) -> Result<(), String>;
 }
 
 pub struct InterCanisterCallManager;
 
+pub trait HttpOutcallManagerTrait {
+    async fn post_json(
+        url: String,
+        headers: Vec<HttpHeader>,
+        body: Vec<u8>,
+    ) -> CallResult<(HttpResponse,)>;
+}
+
+pub struct HttpOutcallManager;
+
 pub trait IcCdkSpawnManagerTrait {
     fn run<F: 'static + Future<Output = ()>>(future: F);
 }
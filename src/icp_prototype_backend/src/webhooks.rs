@@ -0,0 +1,53 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+use crate::types::Event;
+
+const HMAC_BLOCK_SIZE: usize = 64;
+const BACKOFF_BASE: Duration = Duration::from_secs(30);
+const BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+// HMAC-SHA256 as in RFC 2104
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; HMAC_BLOCK_SIZE];
+    if key.len() > HMAC_BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    webhook_id: u64,
+    events: &'a [Event],
+}
+
+// Receivers should deduplicate on the event sequence numbers, since a batch can be sent
+// more than once
+pub fn payload(webhook_id: u64, events: &[Event]) -> Vec<u8> {
+    serde_json::to_vec(&Payload { webhook_id, events }).unwrap_or_default()
+}
+
+// The hex HMAC of the exact request body, keyed with the webhook's secret
+pub fn signature(secret: &[u8], body: &[u8]) -> String {
+    format!("sha256={}", hex::encode(hmac_sha256(secret, body)))
+}
+
+// Delay before retrying after the given number of consecutive failures
+pub fn backoff(failures: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(1u32 << failures.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX)
+}
@@ -0,0 +1,50 @@
+use candid::{CandidType, Deserialize, Principal, Reserved};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+
+// Register with the indexer, then deposit to a subaccount and watch `received`:
+//   dfx canister call icp_prototype_backend subscribe \
+//     "(principal \"$(dfx canister id mock_subscriber)\", \"on_events\", record {}, null)"
+
+// Only the sequence number is read; candid skips the other fields of each event
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Event {
+    seq: u64,
+}
+
+thread_local! {
+    static RECEIVED: RefCell<Vec<u64>> = RefCell::default();
+    static ACKNOWLEDGE: RefCell<bool> = const { RefCell::new(true) };
+}
+
+#[update]
+async fn on_events(events: Vec<Event>) {
+    let indexer: Principal = ic_cdk::caller();
+    let last_seq = events.last().map(|event| event.seq);
+    RECEIVED.with(|received| {
+        received
+            .borrow_mut()
+            .extend(events.iter().map(|event| event.seq))
+    });
+
+    let acknowledge = ACKNOWLEDGE.with(|acknowledge| *acknowledge.borrow());
+    if let (true, Some(seq)) = (acknowledge, last_seq) {
+        let result: Result<(Reserved,), _> = ic_cdk::call(indexer, "ack_events", (seq,)).await;
+        if let Err((code, message)) = result {
+            ic_cdk::println!("ack_events failed: {:?} {}", code, message);
+        }
+    }
+}
+
+// Turning acknowledgements off makes the indexer retry and eventually pause this subscriber
+#[update]
+fn set_acknowledge(enabled: bool) {
+    ACKNOWLEDGE.with(|acknowledge| acknowledge.replace(enabled));
+}
+
+#[query]
+fn received() -> Vec<u64> {
+    RECEIVED.with(|received| received.borrow().clone())
+}
+
+ic_cdk::export_candid!();